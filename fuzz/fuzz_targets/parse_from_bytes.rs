@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The parser must return a `ParseError` for any malformed input. It must never panic.
+fuzz_target!(|data: &[u8]| {
+    let _ = remake_lib::parser::parse_from_bytes(data.to_vec());
+});
@@ -0,0 +1,247 @@
+//! A minimal http cache server for the remote cache of remake.
+//!
+//! `GET`, `HEAD` and `PUT` on `/ac/<key>` and `/cas/<key>` (after an optional prefix) are served.
+//! The key must be hex. The entries are files in the cache directory.
+//! It is for the tests and the small teams. See docs/cache.md.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The address to listen.
+    #[arg(short, long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// The directory to store the entries.
+    #[arg(short, long)]
+    dir: String,
+
+    /// The max size of an entry in bytes.
+    #[arg(long, default_value_t = 1 << 30)]
+    max_entry_size: u64,
+
+    /// Print every request.
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+/// The state shared by the connections.
+struct Server {
+    root: PathBuf,
+    max_entry_size: u64,
+    verbose: bool,
+    /// For the names of the temporary files.
+    counter: AtomicU64,
+}
+
+/// A http request. The body is not read yet.
+struct Request {
+    method: String,
+    path: String,
+    length: u64,
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (String::from(method), String::from(path)),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "bad request line",
+            ))
+        }
+    };
+
+    let mut length = 0;
+
+    // at most 100 headers
+    for _ in 0..100 {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(Some(Request {
+                method,
+                path,
+                length,
+            }));
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "bad content length")
+                })?;
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "too many headers",
+    ))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &[u8], with_body: bool) -> io::Result<()> {
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(header.as_bytes())?;
+
+    if with_body {
+        stream.write_all(body)?;
+    }
+
+    stream.flush()
+}
+
+/// Get `ac/<key>` or `cas/<key>` from the path.
+/// Return None if the path is not an entry, so `..` never escapes the directory.
+fn entry_path(path: &str) -> Option<(&str, &str)> {
+    let mut parts = path.rsplit('/');
+    let key = parts.next()?;
+    let kind = parts.next()?;
+
+    if kind != "ac" && kind != "cas" {
+        return None;
+    }
+
+    if key.is_empty() || key.len() > 128 || !key.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    Some((kind, key))
+}
+
+impl Server {
+    fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(60)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(60)))?;
+
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+
+        let request = match read_request(&mut reader)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        if self.verbose {
+            println!("{} {}", request.method, request.path);
+        }
+
+        let (kind, key) = match entry_path(&request.path) {
+            Some(entry) => entry,
+            None => return respond(&mut writer, "404 Not Found", b"", true),
+        };
+        let path = self.root.join(kind).join(key);
+
+        match request.method.as_str() {
+            "GET" | "HEAD" => match fs::read(&path) {
+                Ok(bytes) => respond(&mut writer, "200 OK", &bytes, request.method == "GET"),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    respond(&mut writer, "404 Not Found", b"", true)
+                }
+                Err(err) => {
+                    eprintln!("Failed to read `{}`:{}", path.display(), err);
+                    respond(&mut writer, "500 Internal Server Error", b"", true)
+                }
+            },
+            "PUT" => {
+                if request.length > self.max_entry_size {
+                    return respond(&mut writer, "413 Payload Too Large", b"", true);
+                }
+
+                let mut body = vec![0; request.length as usize];
+                reader.read_exact(&mut body)?;
+
+                // write and then move, so a reader never gets a half entry
+                let temporary = self.root.join("tmp").join(format!(
+                    "{}.{}",
+                    key,
+                    self.counter.fetch_add(1, Ordering::SeqCst)
+                ));
+
+                match fs::write(&temporary, body).and_then(|_| fs::rename(&temporary, &path)) {
+                    Ok(()) => respond(&mut writer, "200 OK", b"", true),
+                    Err(err) => {
+                        eprintln!("Failed to write `{}`:{}", path.display(), err);
+                        respond(&mut writer, "500 Internal Server Error", b"", true)
+                    }
+                }
+            }
+            _ => respond(&mut writer, "405 Method Not Allowed", b"", true),
+        }
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let root = Path::new(&args.dir);
+    for kind in ["ac", "cas", "tmp"] {
+        if let Err(err) = fs::create_dir_all(root.join(kind)) {
+            eprintln!(
+                "Failed to create the `{}`:{}",
+                root.join(kind).display(),
+                err
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let listener = match TcpListener::bind(&args.listen) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Failed to listen `{}`:{}", args.listen, err);
+            std::process::exit(1);
+        }
+    };
+
+    println!("Listen on {}", listener.local_addr().unwrap());
+
+    let server = Arc::new(Server {
+        root: root.to_path_buf(),
+        max_entry_size: args.max_entry_size,
+        verbose: args.verbose,
+        counter: AtomicU64::new(0),
+    });
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to accept:{}", err);
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(err) = server.handle(stream) {
+                eprintln!("Failed to serve:{}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,460 @@
+//! The output cache. It stores the outputs of the targets, keyed by `state::TargetRecord::key`,
+//! so a target executed in another checkout can be restored instead of executed again.
+//!
+//! `LocalCache` is a cache directory. `remote::RemoteCache` is a http cache server.
+//! See docs/cache.md.
+//!
+//! The layout of the cache directory:
+//!
+//! - `entries/<first two chars of the key>/<key>`: the outputs of a target
+//! - `tmp/`: the entries being written
+//!
+//! The modified time of an entry is the last time it was used.
+//! The least recently used entries are removed first when the cache is too big.
+//! A `LocalCache` counts the size of the entries it stores, so it only scans the directory
+//! the first time and when it is too big.
+
+use crate::errors::ParseError;
+use crate::format::writer::ByteWriter;
+use crate::format::Target;
+use crate::parser::ByteReader;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[cfg(feature = "remote-cache")]
+pub mod remote;
+
+/// The version of the entry file.
+///
+/// - 1: the paths and the contents
+/// - 2: the permissions too. A version 1 entry is not read, and is replaced when the target is stored.
+const ENTRY_VERSION: u64 = 2;
+
+/// Where the outputs of the targets are stored.
+pub trait OutputCache: Sync + Send {
+    /// Write the outputs of a target into the cache.
+    /// Call this after the target was executed successfully.
+    fn store(&self, key: &str, target: &Target) -> io::Result<()>;
+
+    /// Write the outputs of a target from the cache.
+    /// Return false if the cache has no such entry.
+    fn restore(&self, key: &str, target: &Target) -> io::Result<bool>;
+}
+
+/// Read the outputs of a target into an entry.
+pub fn encode_entry(target: &Target) -> io::Result<Vec<u8>> {
+    let mut bytes = ByteWriter::new();
+    bytes.write_string("remake-cache");
+    bytes.write_u64(ENTRY_VERSION);
+    bytes.write_u64(target.outputs.len() as u64);
+
+    for output in target.outputs.iter() {
+        let content = fs::read(output)?;
+        bytes.write_string(output);
+        bytes.write_u64(file_mode(&fs::metadata(output)?));
+        bytes.write_u64(content.len() as u64);
+        bytes.write(&content);
+    }
+
+    Ok(bytes.into_bytes())
+}
+
+/// Write the outputs of a target from an entry.
+pub fn decode_entry(bytes: &[u8], target: &Target) -> io::Result<()> {
+    let outputs = parse_entry(bytes).map_err(to_io_error)?;
+
+    // the key covers the outputs, but check them in case of a broken entry
+    if outputs.len() != target.outputs.len()
+        || outputs
+            .iter()
+            .zip(target.outputs.iter())
+            .any(|(a, b)| a.path != b)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the cache entry of `{}` has other outputs", target.name),
+        ));
+    }
+
+    for output in outputs {
+        if let Some(parent) = Path::new(output.path).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        fs::write(output.path, output.content)?;
+        set_file_mode(output.path, output.mode)?;
+    }
+
+    Ok(())
+}
+
+/// The unix permission bits of a file, like `0o755`. 0 on the other systems.
+fn file_mode(metadata: &fs::Metadata) -> u64 {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::PermissionsExt;
+            (metadata.permissions().mode() & 0o7777) as u64
+        } else {
+            let _ = metadata;
+            0
+        }
+    }
+}
+
+/// Set the permission bits that `file_mode` got. 0 is unknown and changes nothing.
+fn set_file_mode(path: &str, mode: u64) -> io::Result<()> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::PermissionsExt;
+            if mode != 0 {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode as u32))?;
+            }
+            Ok(())
+        } else {
+            let _ = (path, mode);
+            Ok(())
+        }
+    }
+}
+
+/// An output in an entry.
+struct EntryOutput<'a> {
+    path: &'a str,
+    /// See `file_mode`.
+    mode: u64,
+    content: &'a [u8],
+}
+
+fn parse_entry(bytes: &[u8]) -> Result<Vec<EntryOutput<'_>>, ParseError> {
+    let mut reader = ByteReader::new(bytes);
+
+    if reader.read_string()? != "remake-cache" {
+        return Err(ParseError::new(
+            reader.span_from(0),
+            String::from("the bytes are not a cache entry"),
+        ));
+    }
+
+    let begin = reader.position();
+    let version = reader.read_u64()?;
+    if version != ENTRY_VERSION {
+        return Err(ParseError::new(
+            reader.span_from(begin),
+            format!("the cache entry version {} is not supported", version),
+        ));
+    }
+
+    // path + mode + content
+    let count = reader.read_length(8 + 8 + 8, "output array")?;
+    let mut outputs = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let path = reader.read_string()?;
+        let mode = reader.read_u64()?;
+        let length = reader.read_length(1, "output content")?;
+        outputs.push(EntryOutput {
+            path,
+            mode,
+            content: reader.read(length)?,
+        });
+    }
+
+    if !reader.is_end() {
+        return Err(ParseError::new(
+            (reader.position() as u64, bytes.len() as u64),
+            String::from("All content has read but there are some bytes left"),
+        ));
+    }
+
+    Ok(outputs)
+}
+
+/// The size of the entries in a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: u64,
+    /// In bytes.
+    pub size: u64,
+}
+
+/// A cache directory. It can be shared between the workers and the processes.
+pub struct LocalCache {
+    root: PathBuf,
+    /// The entries are removed from the least recently used one when their size is over this.
+    pub max_size: Option<u64>,
+    /// For the names of the temporary files.
+    counter: AtomicU64,
+    /// The size of the entries, counted by `store`. None before the directory is scanned.
+    /// The other processes sharing the directory are not counted until the next eviction scans it.
+    size: Mutex<Option<u64>>,
+}
+
+/// An entry in the cache directory.
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    used: SystemTime,
+}
+
+fn to_io_error(err: ParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+impl LocalCache {
+    /// Open a cache directory. Create it if it does not exist.
+    pub fn open(root: &Path) -> io::Result<LocalCache> {
+        fs::create_dir_all(root.join("entries"))?;
+        fs::create_dir_all(root.join("tmp"))?;
+
+        Ok(LocalCache {
+            root: root.to_path_buf(),
+            max_size: None,
+            counter: AtomicU64::new(0),
+            size: Mutex::new(None),
+        })
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.root
+            .join("entries")
+            .join(&key[..key.len().min(2)])
+            .join(key)
+    }
+
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+
+        for directory in fs::read_dir(self.root.join("entries"))? {
+            let directory = directory?;
+
+            if !directory.file_type()?.is_dir() {
+                continue;
+            }
+
+            for file in fs::read_dir(directory.path())? {
+                let file = file?;
+                let metadata = match file.metadata() {
+                    Ok(metadata) => metadata,
+                    // removed by another process
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                };
+
+                entries.push(Entry {
+                    path: file.path(),
+                    size: metadata.len(),
+                    used: metadata.modified()?,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Get the count and the size of the entries.
+    pub fn stats(&self) -> io::Result<CacheStats> {
+        let mut stats = CacheStats::default();
+
+        for entry in self.entries()? {
+            stats.entries += 1;
+            stats.size += entry.size;
+        }
+
+        Ok(stats)
+    }
+
+    /// Remove the least recently used entries until their size is not over `max_size`.
+    /// Return what were removed.
+    pub fn evict(&self, max_size: u64) -> io::Result<CacheStats> {
+        self.evict_to(max_size).map(|(removed, _)| removed)
+    }
+
+    /// The same as `evict`, and also return the size of the entries left.
+    fn evict_to(&self, max_size: u64) -> io::Result<(CacheStats, u64)> {
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|e| e.size).sum();
+        let mut removed = CacheStats::default();
+
+        entries.sort_unstable_by_key(|e| e.used);
+
+        for entry in entries {
+            if size <= max_size {
+                break;
+            }
+
+            match fs::remove_file(&entry.path) {
+                Ok(()) => {}
+                // removed by another process
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+
+            size -= entry.size;
+            removed.entries += 1;
+            removed.size += entry.size;
+        }
+
+        Ok((removed, size))
+    }
+
+    /// Count a stored entry, and evict the entries when they are over `max_size`.
+    ///
+    /// The eviction goes down to 90% of `max_size`, so the next stores do not scan the directory again at once.
+    fn count_stored(&self, max_size: u64, added: u64, replaced: u64) -> io::Result<()> {
+        let mut size = self.size.lock().unwrap();
+
+        let current = match *size {
+            Some(size) => size.saturating_sub(replaced) + added,
+            // the stored entry is in the directory already
+            None => self.stats()?.size,
+        };
+        *size = Some(current);
+
+        if current > max_size {
+            let (_, left) = self.evict_to(max_size - max_size / 10)?;
+            *size = Some(left);
+        }
+
+        Ok(())
+    }
+
+    /// Remove all the entries and the temporary files.
+    pub fn clear(&self) -> io::Result<CacheStats> {
+        let stats = self.stats()?;
+
+        fs::remove_dir_all(self.root.join("entries"))?;
+        fs::remove_dir_all(self.root.join("tmp"))?;
+        fs::create_dir_all(self.root.join("entries"))?;
+        fs::create_dir_all(self.root.join("tmp"))?;
+
+        Ok(stats)
+    }
+}
+
+impl OutputCache for LocalCache {
+    /// The entry appears at once, so the others never read a half entry.
+    fn store(&self, key: &str, target: &Target) -> io::Result<()> {
+        let bytes = encode_entry(target)?;
+
+        let temporary = self.root.join("tmp").join(format!(
+            "{}.{}.{}",
+            key,
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::SeqCst)
+        ));
+        let path = self.entry_path(key);
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        // the same key may be stored again
+        let replaced = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        let added = bytes.len() as u64;
+        fs::write(&temporary, bytes)?;
+        fs::rename(&temporary, &path)?;
+
+        if let Some(max_size) = self.max_size {
+            self.count_stored(max_size, added, replaced)?;
+        }
+
+        Ok(())
+    }
+
+    fn restore(&self, key: &str, target: &Target) -> io::Result<bool> {
+        let path = self.entry_path(key);
+
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        decode_entry(&bytes, target)?;
+
+        // mark it as used
+        fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::builder::{GraphBuilder, TargetBuilder};
+
+    #[cfg(unix)]
+    #[test]
+    fn restore_the_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory =
+            std::env::temp_dir().join(format!("remake-cache-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let script = directory.join("script.sh");
+        let script = script.to_str().unwrap();
+
+        let mut graph = GraphBuilder::new();
+        graph
+            .target(TargetBuilder::new("script").output(script))
+            .unwrap();
+        let remake = graph.build().unwrap();
+        let target = remake.targets.values().next().unwrap();
+
+        fs::write(script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755)).unwrap();
+        let entry = encode_entry(target).unwrap();
+
+        fs::write(script, "changed").unwrap();
+        fs::set_permissions(script, fs::Permissions::from_mode(0o644)).unwrap();
+        decode_entry(&entry, target).unwrap();
+
+        let mode = fs::metadata(script).unwrap().permissions().mode() & 0o7777;
+        let content = fs::read_to_string(script).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(mode, 0o755);
+        assert_eq!(content, "#!/bin/sh\n");
+    }
+
+    #[test]
+    fn evict_by_the_counted_size() {
+        let directory =
+            std::env::temp_dir().join(format!("remake-cache-evict-test-{}", std::process::id()));
+        let output = directory.join("output");
+        let output = output.to_str().unwrap();
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(output, "some output").unwrap();
+
+        let mut graph = GraphBuilder::new();
+        graph
+            .target(TargetBuilder::new("target").output(output))
+            .unwrap();
+        let remake = graph.build().unwrap();
+        let target = remake.targets.values().next().unwrap();
+        let entry = encode_entry(target).unwrap().len() as u64;
+
+        let mut cache = LocalCache::open(&directory.join("cache")).unwrap();
+        cache.max_size = Some(3 * entry);
+
+        // the entries after every store
+        let mut sizes = Vec::new();
+        for key in ["k1", "k2", "k3", "k3", "k4", "k5"] {
+            cache.store(key, target).unwrap();
+            let stats = cache.stats().unwrap();
+            // the counter is right without scanning
+            assert_eq!(*cache.size.lock().unwrap(), Some(stats.size));
+            sizes.push(stats.entries);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+
+        // the 4th entry is over the max, then down to 90% of it
+        assert_eq!(sizes, [1, 2, 3, 3, 2, 3]);
+    }
+}
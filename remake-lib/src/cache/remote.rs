@@ -0,0 +1,312 @@
+//! A http cache client.
+//!
+//! The protocol is like the http cache of Bazel:
+//!
+//! - `GET <prefix>/cas/<key>`: 200 with the entry as the body, or 404 if there is no such entry
+//! - `PUT <prefix>/cas/<key>`: store the body as the entry. Any 2xx is ok
+//!
+//! The entry is the bytes of `cache::encode_entry`.
+//! Only `http://` is supported, and one request is sent per connection.
+//! A body is read by `Content-Length`, `Transfer-Encoding: chunked` or until the connection closes,
+//! and never more than `RemoteCache::max_entry_size`.
+
+use super::{decode_entry, encode_entry, OutputCache};
+use crate::format::Target;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// A http cache server.
+pub struct RemoteCache {
+    /// `host:port`
+    host: String,
+    /// The path before `/cas/`, without the tailing `/`.
+    prefix: String,
+    /// The timeout of connecting, reading and writing.
+    pub timeout: Duration,
+    /// A bigger response is an error, so a bad server can not use up the memory.
+    /// The same default as remake-cache-server.
+    pub max_entry_size: u64,
+}
+
+/// A http response.
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+}
+
+fn invalid_data(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+impl RemoteCache {
+    /// Create a client from an url like `http://127.0.0.1:8080` or `http://cache.local/remake`.
+    /// The port is 80 by default.
+    pub fn new(url: &str) -> Result<RemoteCache, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("the cache url `{}` does not start with `http://`", url))?;
+
+        let (host, prefix) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+
+        if host.is_empty() {
+            return Err(format!("the cache url `{}` has no host", url));
+        }
+
+        let host = if host.contains(':') {
+            String::from(host)
+        } else {
+            format!("{}:80", host)
+        };
+
+        Ok(RemoteCache {
+            host,
+            prefix: String::from(prefix.trim_end_matches('/')),
+            timeout: Duration::from_secs(30),
+            max_entry_size: 1 << 30,
+        })
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addresses: Vec<SocketAddr> = self.host.to_socket_addrs()?.collect();
+        let mut last_error = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("can not resolve `{}`", self.host),
+        );
+
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                }
+                Err(err) => last_error = err,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn request(&self, method: &str, key: &str, body: &[u8]) -> io::Result<Response> {
+        let mut stream = self.connect()?;
+
+        let header = format!(
+            "{} {}/cas/{} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            self.prefix,
+            key,
+            self.host,
+            body.len()
+        );
+        stream.write_all(header.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+
+        // status line
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| invalid_data(format!("bad http status line `{}`", line.trim_end())))?;
+
+        // headers
+        let mut length: Option<u64> = None;
+        let mut chunked = false;
+
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(invalid_data(String::from(
+                    "the http headers are not finished",
+                )));
+            }
+
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    length = Some(value.trim().parse().map_err(|_| {
+                        invalid_data(format!("bad http content length `{}`", value.trim()))
+                    })?);
+                }
+                // the last coding is the one to decode first
+                if name.trim().eq_ignore_ascii_case("transfer-encoding") {
+                    chunked = value
+                        .rsplit(',')
+                        .next()
+                        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+                }
+            }
+        }
+
+        // a chunked body ignores the length
+        let body = if chunked {
+            read_chunked(&mut reader, self.max_entry_size)?
+        } else {
+            read_body(&mut reader, length, self.max_entry_size)?
+        };
+
+        Ok(Response { status, body })
+    }
+}
+
+fn too_big(max: u64) -> io::Error {
+    invalid_data(format!("the http body is bigger than {} bytes", max))
+}
+
+/// Read `length` bytes, or until close if no length. Never more than `max`.
+fn read_body(reader: &mut impl BufRead, length: Option<u64>, max: u64) -> io::Result<Vec<u8>> {
+    if length.is_some_and(|length| length > max) {
+        return Err(too_big(max));
+    }
+
+    // grow while reading, so a wrong length does not allocate at once
+    let mut body = Vec::new();
+    reader
+        .take(length.unwrap_or(max + 1))
+        .read_to_end(&mut body)?;
+
+    match length {
+        Some(length) if (body.len() as u64) < length => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the http body is shorter than the content length",
+        )),
+        None if body.len() as u64 > max => Err(too_big(max)),
+        _ => Ok(body),
+    }
+}
+
+/// Decode a `Transfer-Encoding: chunked` body. Never more than `max` bytes.
+fn read_chunked(reader: &mut impl BufRead, max: u64) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+
+        // the size may have extensions after `;`
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| invalid_data(format!("bad http chunk size `{}`", line.trim_end())))?;
+
+        if size == 0 {
+            break;
+        }
+        if body.len() as u64 + size > max {
+            return Err(too_big(max));
+        }
+
+        let read = reader.take(size).read_to_end(&mut body)?;
+        if (read as u64) < size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the http chunk is not finished",
+            ));
+        }
+
+        // the CRLF after the data
+        line.clear();
+        reader.read_line(&mut line)?;
+        if !line.trim_end().is_empty() {
+            return Err(invalid_data(String::from(
+                "the http chunk is longer than its size",
+            )));
+        }
+    }
+
+    // the trailers
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            return Ok(body);
+        }
+    }
+}
+
+impl OutputCache for RemoteCache {
+    fn store(&self, key: &str, target: &Target) -> io::Result<()> {
+        let bytes = encode_entry(target)?;
+        let response = self.request("PUT", key, &bytes)?;
+
+        if !(200..300).contains(&response.status) {
+            return Err(io::Error::other(format!(
+                "the cache server returns {} for PUT",
+                response.status
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn restore(&self, key: &str, target: &Target) -> io::Result<bool> {
+        let response = self.request("GET", key, &[])?;
+
+        match response.status {
+            200 => {
+                decode_entry(&response.body, target)?;
+                Ok(true)
+            }
+            404 => Ok(false),
+            status => Err(io::Error::other(format!(
+                "the cache server returns {} for GET",
+                status
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_by_the_length() {
+        let mut reader: &[u8] = b"entry and more";
+        assert_eq!(read_body(&mut reader, Some(5), 100).unwrap(), b"entry");
+
+        let mut reader: &[u8] = b"entry";
+        assert_eq!(read_body(&mut reader, None, 100).unwrap(), b"entry");
+
+        let mut reader: &[u8] = b"short";
+        assert!(read_body(&mut reader, Some(100), 100).is_err());
+    }
+
+    #[test]
+    fn never_read_over_the_max() {
+        // nothing is allocated for the length
+        let mut reader: &[u8] = b"entry";
+        assert!(read_body(&mut reader, Some(u64::MAX), 100).is_err());
+
+        let mut reader: &[u8] = b"entry";
+        assert!(read_body(&mut reader, None, 4).is_err());
+
+        let mut reader: &[u8] = b"5\r\nentry\r\n0\r\n\r\n";
+        assert!(read_chunked(&mut reader, 4).is_err());
+    }
+
+    #[test]
+    fn decode_the_chunks() {
+        let mut reader: &[u8] = b"5\r\nentry\r\n3;name=value\r\n ok\r\n0\r\nTrailer: x\r\n\r\n";
+        assert_eq!(read_chunked(&mut reader, 100).unwrap(), b"entry ok");
+
+        let mut reader: &[u8] = b"zz\r\nentry\r\n0\r\n\r\n";
+        assert!(read_chunked(&mut reader, 100).is_err());
+
+        // not finished
+        let mut reader: &[u8] = b"5\r\nent";
+        assert!(read_chunked(&mut reader, 100).is_err());
+    }
+}
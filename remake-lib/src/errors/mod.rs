@@ -11,6 +11,14 @@ pub struct ParseError {
 }
 
 impl ParseError {
+    pub fn new(source_span: (u64, u64), reason: String) -> ParseError {
+        ParseError {
+            source: None,
+            source_span: Some(source_span),
+            reason: Some(reason),
+        }
+    }
+
     pub fn from_exceptional_eof() -> ParseError {
         ParseError {
             source: None,
@@ -69,6 +77,8 @@ pub struct RuntimeError {
     pub source: Option<Arc<dyn Error>>,
     pub command: Option<String>,
     pub reason: Option<String>,
+    /// The stdout and stderr of the command if they were captured.
+    pub output: Option<String>,
 }
 
 impl fmt::Display for RuntimeError {
@@ -103,6 +113,14 @@ impl fmt::Display for RuntimeError {
                 write!(f, "Source Reason:{}", err)
             }
         }
+        .unwrap();
+
+        match &self.output {
+            Some(output) if !output.is_empty() => {
+                write!(f, "\nOutput:\n{}", output.trim_end())
+            }
+            _ => Ok(()),
+        }
     }
 }
 
@@ -111,3 +129,138 @@ impl Error for RuntimeError {
         None
     }
 }
+
+/// An error,throw it when build targets from the `format::builder`.
+#[derive(Debug)]
+pub struct BuilderError {
+    pub target: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl BuilderError {
+    pub fn new(target: &str, reason: String) -> BuilderError {
+        BuilderError {
+            target: Some(String::from(target)),
+            reason: Some(reason),
+        }
+    }
+}
+
+impl fmt::Display for BuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Remake crash when build targets").unwrap();
+
+        match &self.target {
+            None => {
+                write!(f, "Source Target:Unknown")
+            }
+            Some(target) => {
+                write!(f, "Source Target:{}", target)
+            }
+        }
+        .unwrap();
+
+        match &self.reason {
+            None => {
+                write!(f, "Source Reason:Unknown")
+            }
+            Some(err) => {
+                write!(f, "Source Reason:{}", err)
+            }
+        }
+    }
+}
+
+impl Error for BuilderError {}
+
+/// A error,throw it when parse a text build file.
+/// The line and column start from 1.
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub line: usize,
+    pub column: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.reason)
+    }
+}
+
+impl Error for SyntaxError {}
+
+/// A problem of the dependence graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GraphError {
+    /// The target is requested but not defined.
+    UnknownTarget { target: String },
+    /// The target depends on a target that is not defined.
+    UnknownDependence { target: String, dependence: String },
+    /// The target depends on itself.
+    SelfDependence { target: String },
+    /// The name is used by more than one target.
+    DuplicateTarget { target: String },
+    /// The targets depend on each other. The first target is repeated at the end of the path.
+    Cycle { path: Vec<String> },
+    /// The target is in a pool that is not defined.
+    UnknownPool { target: String, pool: String },
+    /// The depth of the pool is 0, so its targets never execute.
+    EmptyPool { pool: String },
+    /// The pool is always defined by remake, like `console`.
+    ReservedPool { pool: String },
+}
+
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GraphError::UnknownTarget { target } => {
+                write!(f, "the target `{}` is not defined", target)
+            }
+            GraphError::UnknownDependence { target, dependence } => write!(
+                f,
+                "the target `{}` depends on `{}` that is not defined",
+                target, dependence
+            ),
+            GraphError::SelfDependence { target } => {
+                write!(f, "the target `{}` depends on itself", target)
+            }
+            GraphError::DuplicateTarget { target } => {
+                write!(f, "the target `{}` is defined more than once", target)
+            }
+            GraphError::Cycle { path } => {
+                write!(f, "the dependences form a cycle: {}", path.join(" -> "))
+            }
+            GraphError::UnknownPool { target, pool } => write!(
+                f,
+                "the target `{}` is in the pool `{}` that is not defined",
+                target, pool
+            ),
+            GraphError::EmptyPool { pool } => {
+                write!(f, "the depth of the pool `{}` is 0", pool)
+            }
+            GraphError::ReservedPool { pool } => {
+                write!(f, "the `{}` pool can not be defined", pool)
+            }
+        }
+    }
+}
+
+/// An error,throw it when the targets do not pass `graph::validate`.
+#[derive(Debug)]
+pub struct ValidationError {
+    pub errors: Vec<GraphError>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Remake crash when validate targets")?;
+
+        for error in self.errors.iter() {
+            write!(f, "\n{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ValidationError {}
@@ -0,0 +1,298 @@
+//! Stop the build when remake is interrupted by Ctrl-C or `kill`.
+//!
+//! After `install`, SIGINT and SIGTERM do not kill remake but are recorded.
+//! Then every command that is not in the console pool runs in its own process group,
+//! so the terminal does not signal it directly, and the programs it starts can be signaled with it.
+//! Without `install` the commands stay in the group of remake and get Ctrl-C from the terminal.
+//! The executer stops starting new targets, forwards the signal to the running commands,
+//! and kills the commands that are still running after a grace period. See docs/interrupt.md.
+//!
+//! The signal handler writes a byte into a pipe, so a `Watcher` blocks until a signal comes.
+//!
+//! Only on unix. On the other systems `install` fails and nothing is forwarded.
+
+use ahash::AHashMap;
+use std::io;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The signal to kill the commands after the grace period.
+pub const KILL_SIGNAL: i32 = sys::KILL;
+
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+/// The pipe that the signal handler writes. -1 before `install`.
+static SIGNAL_READ: AtomicI32 = AtomicI32::new(-1);
+static SIGNAL_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+/// Record SIGINT and SIGTERM instead of being killed by them.
+/// Calling it again does nothing.
+pub fn install() -> io::Result<()> {
+    if is_installed() {
+        return Ok(());
+    }
+
+    let (read, write) = sys::pipe()?;
+    SIGNAL_READ.store(read, Ordering::SeqCst);
+    SIGNAL_WRITE.store(write, Ordering::SeqCst);
+
+    let installed = sys::install();
+
+    if installed.is_err() {
+        SIGNAL_READ.store(-1, Ordering::SeqCst);
+        SIGNAL_WRITE.store(-1, Ordering::SeqCst);
+        sys::close(read);
+        sys::close(write);
+    }
+
+    installed
+}
+
+pub fn is_installed() -> bool {
+    SIGNAL_READ.load(Ordering::SeqCst) >= 0
+}
+
+/// The first signal received. None if remake was not interrupted.
+pub fn received() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// How many signals were received. A second one kills the commands at once.
+pub fn count() -> usize {
+    COUNT.load(Ordering::SeqCst)
+}
+
+/// Called by the signal handler, so only atomics and `write` here.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn record(signal: i32) {
+    let _ = SIGNAL.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst);
+    COUNT.fetch_add(1, Ordering::SeqCst);
+    sys::write_byte(SIGNAL_WRITE.load(Ordering::SeqCst));
+}
+
+/// Why `Watcher::wait` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wake {
+    /// A signal was received. See `received` and `count`.
+    Interrupted,
+    /// `Watcher::stop` was called.
+    Stopped,
+    TimedOut,
+}
+
+/// Block until a signal comes or the build stops, without waking up for nothing.
+/// Create it after `install`.
+#[derive(Debug)]
+pub struct Watcher {
+    /// The pipe that `stop` writes.
+    read: i32,
+    write: i32,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Watcher> {
+        let (read, write) = sys::pipe()?;
+        Ok(Watcher { read, write })
+    }
+
+    /// Wait for a signal or `stop`. None waits forever.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<Wake> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let signal = SIGNAL_READ.load(Ordering::SeqCst);
+
+        loop {
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            // a signal interrupts the poll, then the byte is read by the next one
+            let [interrupted, stopped] = match sys::poll([signal, self.read], timeout) {
+                Ok(ready) => ready,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            if stopped {
+                return Ok(Wake::Stopped);
+            }
+            if interrupted {
+                sys::drain(signal);
+                return Ok(Wake::Interrupted);
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(Wake::TimedOut);
+            }
+        }
+    }
+
+    /// Wake up the `wait` and the ones after it.
+    pub fn stop(&self) {
+        sys::write_byte(self.write);
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        sys::close(self.read);
+        sys::close(self.write);
+    }
+}
+
+/// The commands executing now.
+#[derive(Debug, Default)]
+pub struct Children {
+    /// The process id, and whether it leads its own process group.
+    pids: Mutex<AHashMap<u32, bool>>,
+}
+
+impl Children {
+    /// Track a started command.
+    /// A command started after an interrupt is signaled at once.
+    pub fn add(&self, pid: u32, group: bool) {
+        let mut pids = self.pids.lock().unwrap();
+        pids.insert(pid, group);
+
+        if let Some(signal) = received() {
+            sys::kill(pid, group, signal);
+        }
+    }
+
+    /// Stop tracking a command that exited.
+    /// Call it before the command is reaped, or the process id may be reused by another process.
+    pub fn remove(&self, pid: u32) {
+        self.pids.lock().unwrap().remove(&pid);
+    }
+
+    /// Send a signal to the commands, and their process groups if they have.
+    pub fn signal(&self, signal: i32) {
+        for (pid, group) in self.pids.lock().unwrap().iter() {
+            sys::kill(*pid, *group, signal);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::io;
+    use std::time::Duration;
+
+    pub const KILL: i32 = libc::SIGKILL;
+
+    extern "C" fn handle(signal: libc::c_int) {
+        super::record(signal);
+    }
+
+    pub fn install() -> io::Result<()> {
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A pipe that the commands do not inherit, and never blocks.
+    pub fn pipe() -> io::Result<(i32, i32)> {
+        let mut fds = [0; 2];
+
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for fd in fds {
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+            }
+        }
+
+        Ok((fds[0], fds[1]))
+    }
+
+    pub fn close(fd: i32) {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    /// A full pipe already wakes up the reader, so an error is ignored.
+    pub fn write_byte(fd: i32) {
+        if fd >= 0 {
+            unsafe {
+                libc::write(fd, b"+".as_ptr() as *const libc::c_void, 1);
+            }
+        }
+    }
+
+    pub fn drain(fd: i32) {
+        let mut buffer = [0u8; 64];
+        while unsafe { libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len()) } > 0
+        {
+        }
+    }
+
+    /// Whether the fds are readable. A negative fd is ignored.
+    pub fn poll(fds: [i32; 2], timeout: Option<Duration>) -> io::Result<[bool; 2]> {
+        let mut polls = fds.map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        let timeout = match timeout {
+            Some(timeout) => timeout.as_millis().min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+
+        if unsafe { libc::poll(polls.as_mut_ptr(), 2, timeout) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(polls.map(|poll| poll.revents != 0))
+    }
+
+    pub fn kill(pid: u32, group: bool, signal: i32) {
+        // a negative pid is the process group
+        let pid = if group { -(pid as i32) } else { pid as i32 };
+
+        unsafe {
+            libc::kill(pid, signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::time::Duration;
+
+    pub const KILL: i32 = 9;
+
+    pub fn install() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the signals are only handled on unix",
+        ))
+    }
+
+    pub fn pipe() -> io::Result<(i32, i32)> {
+        install().map(|_| (-1, -1))
+    }
+
+    pub fn close(_fd: i32) {}
+
+    pub fn write_byte(_fd: i32) {}
+
+    pub fn drain(_fd: i32) {}
+
+    pub fn poll(_fds: [i32; 2], _timeout: Option<Duration>) -> io::Result<[bool; 2]> {
+        Ok([false, true])
+    }
+
+    pub fn kill(_pid: u32, _group: bool, _signal: i32) {}
+}
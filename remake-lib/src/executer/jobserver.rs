@@ -0,0 +1,394 @@
+//! The GNU make jobserver, so the nested builds share one number of jobs.
+//!
+//! A jobserver is a pipe with a byte for every job that can start more.
+//! A process reads a byte before starting a job and writes it back after.
+//! Every process has one job without a byte, the implicit one.
+//! See docs/jobserver.md.
+//!
+//! Only the unix systems are supported.
+
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// A job slot. It is given back when dropped.
+pub struct Token<'a> {
+    jobserver: &'a Jobserver,
+    /// None for the implicit slot.
+    byte: Option<u8>,
+}
+
+impl Drop for Token<'_> {
+    fn drop(&mut self) {
+        match self.byte {
+            // nobody can be told if this fails, and the slot is lost
+            Some(byte) => {
+                let _ = sys::write_byte(self.jobserver.write, byte);
+            }
+            None => self.jobserver.implicit.store(true, Ordering::SeqCst),
+        }
+    }
+}
+
+/// A jobserver that is joined or created.
+pub struct Jobserver {
+    read: i32,
+    write: i32,
+    /// The fifo of a `fifo:PATH` jobserver. Kept open for the fds.
+    _fifo: Option<File>,
+    /// `read` opened again without blocking. See `sys::open_nonblocking`.
+    nonblocking: Option<File>,
+    /// If true, the fds were created by `new` and are closed when dropped.
+    owned: bool,
+    /// The `MAKEFLAGS` for the commands. None for a joined jobserver,
+    /// because the commands inherit the one that remake got.
+    makeflags: Option<String>,
+    /// Whether the implicit slot is free.
+    implicit: AtomicBool,
+}
+
+impl Jobserver {
+    /// Join the jobserver in `CARGO_MAKEFLAGS`, `MAKEFLAGS` or `MFLAGS`.
+    /// Return None if there is no jobserver.
+    ///
+    /// Both `--jobserver-auth=R,W` (or the old `--jobserver-fds=R,W`) and `--jobserver-auth=fifo:PATH` are supported.
+    pub fn from_env() -> Result<Option<Jobserver>, String> {
+        let flags = match ["CARGO_MAKEFLAGS", "MAKEFLAGS", "MFLAGS"]
+            .iter()
+            .find_map(|name| std::env::var(name).ok())
+        {
+            Some(flags) => flags,
+            None => return Ok(None),
+        };
+
+        // the last one wins, like make
+        let auth = flags.split_whitespace().rev().find_map(|flag| {
+            flag.strip_prefix("--jobserver-auth=")
+                .or_else(|| flag.strip_prefix("--jobserver-fds="))
+        });
+
+        match auth {
+            Some(auth) => Jobserver::join(auth).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn join(auth: &str) -> Result<Jobserver, String> {
+        if let Some(path) = auth.strip_prefix("fifo:") {
+            let fifo = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map_err(|err| format!("can not open the jobserver fifo `{}`:{}", path, err))?;
+
+            let fd = sys::fd_of(&fifo);
+
+            return Ok(Jobserver {
+                read: fd,
+                write: fd,
+                _fifo: Some(fifo),
+                nonblocking: sys::open_nonblocking(path).ok(),
+                owned: false,
+                makeflags: None,
+                implicit: AtomicBool::new(true),
+            });
+        }
+
+        let fds = auth
+            .split_once(',')
+            .and_then(|(read, write)| Some((read.parse().ok()?, write.parse().ok()?)));
+
+        let (read, write) = match fds {
+            Some(fds) => fds,
+            None => return Err(format!("can not understand the jobserver `{}`", auth)),
+        };
+
+        if !sys::is_open(read) || !sys::is_open(write) {
+            return Err(format!(
+                "the jobserver fds {} are not open. Add `+` before the command in the Makefile",
+                auth
+            ));
+        }
+
+        Ok(Jobserver {
+            read,
+            write,
+            _fifo: None,
+            nonblocking: sys::reopen_nonblocking(read).ok(),
+            owned: false,
+            makeflags: None,
+            implicit: AtomicBool::new(true),
+        })
+    }
+
+    /// Create a jobserver with `jobs` slots, including the implicit one.
+    /// The commands get it by `environments`.
+    pub fn new(jobs: u32) -> io::Result<Jobserver> {
+        let (read, write) = sys::pipe()?;
+
+        let jobserver = Jobserver {
+            read,
+            write,
+            _fifo: None,
+            nonblocking: sys::reopen_nonblocking(read).ok(),
+            owned: true,
+            makeflags: Some(format!(
+                "-j{} --jobserver-fds={},{} --jobserver-auth={},{}",
+                jobs, read, write, read, write
+            )),
+            implicit: AtomicBool::new(true),
+        };
+
+        for _ in 1..jobs {
+            sys::write_byte(write, b'+')?;
+        }
+
+        Ok(jobserver)
+    }
+
+    /// Get a job slot. Block until there is one, or remake is interrupted. See `interrupt`.
+    pub fn acquire(&self) -> io::Result<Token<'_>> {
+        loop {
+            if self.implicit.swap(false, Ordering::SeqCst) {
+                return Ok(Token {
+                    jobserver: self,
+                    byte: None,
+                });
+            }
+
+            // the slots may be held long by the other programs
+            if let Some(signal) = super::interrupt::received() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Interrupted,
+                    format!("interrupted by the signal {}", signal),
+                ));
+            }
+
+            // wake up sometimes, because the implicit slot may be freed by another worker
+            if let Some(byte) = sys::read_byte(self.reader(), Duration::from_millis(100))? {
+                return Ok(Token {
+                    jobserver: self,
+                    byte: Some(byte),
+                });
+            }
+        }
+    }
+
+    /// The fd to read the slots from.
+    ///
+    /// The fd shared with the other programs may block when another program takes the byte first,
+    /// and a blocking read is not stopped by an interrupt. So it is only read when it can not be
+    /// opened again without blocking.
+    fn reader(&self) -> i32 {
+        match &self.nonblocking {
+            Some(file) => sys::fd_of(file),
+            None => self.read,
+        }
+    }
+
+    /// The environment variables that pass the jobserver to the commands.
+    pub fn environments(&self) -> Vec<(String, String)> {
+        match &self.makeflags {
+            Some(makeflags) => vec![(String::from("MAKEFLAGS"), makeflags.clone())],
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Drop for Jobserver {
+    fn drop(&mut self) {
+        if self.owned {
+            sys::close(self.read);
+            sys::close(self.write);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::time::Duration;
+
+    pub fn fd_of(file: &File) -> i32 {
+        file.as_raw_fd()
+    }
+
+    pub fn is_open(fd: i32) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+    }
+
+    /// Open the fifo without blocking for reading the slots.
+    pub fn open_nonblocking(path: &str) -> io::Result<File> {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+    }
+
+    /// Open the pipe of the fd again, as a new open file description that never blocks.
+    /// The flags of the fd are shared with the other programs, so they are not changed.
+    ///
+    /// Only where `/proc` is mounted, like linux.
+    pub fn reopen_nonblocking(fd: i32) -> io::Result<File> {
+        open_nonblocking(&format!("/proc/self/fd/{}", fd))
+    }
+
+    /// Create a pipe that the children inherit.
+    pub fn pipe() -> io::Result<(i32, i32)> {
+        let mut fds = [0; 2];
+
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok((fds[0], fds[1]))
+    }
+
+    pub fn close(fd: i32) {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    /// Read a byte. Return None if there is nothing to read before the timeout,
+    /// or another program got the byte first from a fd that does not block.
+    pub fn read_byte(fd: i32, timeout: Duration) -> io::Result<Option<u8>> {
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        match unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) } {
+            0 => return Ok(None),
+            -1 => {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::Interrupted => Ok(None),
+                    _ => Err(err),
+                };
+            }
+            _ => {}
+        }
+
+        // another program may get the byte first, then a blocking fd blocks until the next one
+        let mut byte = 0u8;
+        match unsafe { libc::read(fd, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+            1 => Ok(Some(byte)),
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the jobserver was closed",
+            )),
+            _ => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => Ok(None),
+                    _ => Err(err),
+                }
+            }
+        }
+    }
+
+    pub fn write_byte(fd: i32, byte: u8) -> io::Result<()> {
+        loop {
+            match unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) } {
+                1 => return Ok(()),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::fs::File;
+    use std::io;
+    use std::time::Duration;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the jobserver is only supported on unix",
+        )
+    }
+
+    pub fn fd_of(_file: &File) -> i32 {
+        -1
+    }
+
+    pub fn is_open(_fd: i32) -> bool {
+        false
+    }
+
+    pub fn open_nonblocking(_path: &str) -> io::Result<File> {
+        Err(unsupported())
+    }
+
+    pub fn reopen_nonblocking(_fd: i32) -> io::Result<File> {
+        Err(unsupported())
+    }
+
+    pub fn pipe() -> io::Result<(i32, i32)> {
+        Err(unsupported())
+    }
+
+    pub fn close(_fd: i32) {}
+
+    pub fn read_byte(_fd: i32, _timeout: Duration) -> io::Result<Option<u8>> {
+        Err(unsupported())
+    }
+
+    pub fn write_byte(_fd: i32, _byte: u8) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn nonblocking(fd: i32) -> bool {
+        unsafe { libc::fcntl(fd, libc::F_GETFL) & libc::O_NONBLOCK != 0 }
+    }
+
+    #[test]
+    fn read_the_slots_without_blocking() {
+        let jobserver = Jobserver::new(2).unwrap();
+
+        // the commands inherit the fd, so it still blocks
+        assert!(!nonblocking(jobserver.read));
+        assert!(nonblocking(jobserver.reader()));
+
+        let implicit = jobserver.acquire().unwrap();
+        let slot = jobserver.acquire().unwrap();
+        assert_eq!(implicit.byte, None);
+        assert_eq!(slot.byte, Some(b'+'));
+
+        // like another program took the byte first
+        let mut byte = 0u8;
+        let read = unsafe {
+            libc::read(
+                jobserver.reader(),
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+            )
+        };
+        assert_eq!(read, -1);
+        assert_eq!(io::Error::last_os_error().kind(), io::ErrorKind::WouldBlock);
+
+        drop(slot);
+        assert_eq!(
+            sys::read_byte(jobserver.reader(), Duration::ZERO).unwrap(),
+            Some(b'+')
+        );
+    }
+}
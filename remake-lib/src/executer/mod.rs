@@ -1,20 +1,123 @@
-use crate::format::Target;
+use crate::cache::OutputCache;
+use crate::errors::RuntimeError;
+use crate::format::definition::CONSOLE_POOL;
+use crate::format::{RunOptions, Target};
+use crate::graph;
+use crate::state::{BuildState, StateCheck, TargetRecord};
 use ahash::AHashMap;
+use std::fs;
+use std::io;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use self::scheduler::TargetScheduler;
+use self::interrupt::{Children, Wake, Watcher};
+use self::jobserver::{Jobserver, Token};
+use self::observer::{BuildObserver, LoggerObserver, Observers};
+use self::scheduler::{TargetScheduler, TargetState};
+use self::throttle::Throttle;
+use self::up_to_date::Staleness;
 
+pub mod interrupt;
+pub mod jobserver;
+pub mod observer;
 pub mod scheduler;
+pub mod throttle;
+pub mod up_to_date;
+
+/// The result of `Executer::execute`.
+/// The names are sorted.
+pub struct BuildReport {
+    pub succeeded: Vec<Arc<String>>,
+    /// The targets that were not executed because they were up to date.
+    pub up_to_date: Vec<Arc<String>>,
+    /// The targets that were not executed because their outputs were restored from the cache.
+    pub restored: Vec<Arc<String>>,
+    pub failed: Vec<Arc<String>>,
+    /// The targets that were not executed because some of their dependences failed.
+    pub skipped: Vec<Arc<String>>,
+    pub errors: Vec<String>,
+    /// How long every target took, from a worker getting it to it finishing or failing.
+    /// The skipped targets are not here. See `graph::critical_path`.
+    pub durations: AHashMap<Arc<String>, Duration>,
+    /// The signal that interrupted the build. See `interrupt`.
+    pub interrupted: Option<i32>,
+}
+
+impl BuildReport {
+    fn from_errors(errors: Vec<String>) -> BuildReport {
+        BuildReport {
+            succeeded: Vec::new(),
+            up_to_date: Vec::new(),
+            restored: Vec::new(),
+            failed: Vec::new(),
+            skipped: Vec::new(),
+            errors,
+            durations: AHashMap::new(),
+            interrupted: None,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// The error of a target that was executing when remake was interrupted.
+fn interrupted(target: &Target, signal: i32) -> RuntimeError {
+    RuntimeError {
+        source: None,
+        command: None,
+        reason: Some(format!(
+            "{} was interrupted by the signal {}",
+            target.name, signal
+        )),
+        output: None,
+    }
+}
 
 /// This is the executer of the targets
 pub struct Executer {
     pub thread_count: u32,
+    /// If true, keep executing the targets that do not depend on a failed target.
+    pub keep_going: bool,
+    /// If true, execute the targets even if they are up to date.
+    pub always_execute: bool,
+    /// If set, a target is up to date by the hashes of its commands and inputs
+    /// instead of the modified time. See `state::BuildState`.
+    pub build_state: Option<Arc<BuildState>>,
+    /// If true, log why a target is executed.
+    pub explain: bool,
+    /// If true, the stdout and stderr of the commands of a target are captured,
+    /// and passed to `BuildObserver::target_output` at once when the target finishes.
+    /// So the outputs of the targets executing at the same time never interleave.
+    /// Only set it with a `logger` or an observer that prints them.
+    pub capture_output: bool,
+    /// The outputs of a target are restored from the first cache that has them instead of executing it,
+    /// and stored into all the caches after executing it.
+    pub caches: Vec<Arc<dyn OutputCache>>,
+    /// If set, a worker gets a job slot from it before executing the commands of a target,
+    /// so the number of jobs is shared with make and the other programs using it.
+    /// The commands get it too. See `jobserver::Jobserver`.
+    pub jobserver: Option<Arc<Jobserver>>,
+    /// The depths of the pools. See docs/standard_4.md.
+    /// The targets in the `console` pool execute one by one and are never captured.
+    pub pools: AHashMap<String, u64>,
+    /// No new target starts while the load average or the available memory is over the limits,
+    /// unless nothing is executing. See `throttle::Throttle`.
+    pub throttle: Throttle,
+    /// How long the running commands have to exit after an interrupt is forwarded to them.
+    /// Then they are killed. See `interrupt`.
+    pub grace_period: Duration,
     pub all_targets: Arc<AHashMap<Arc<String>, Arc<Target>>>,
     /// If you want to print some useful message to user,set this.
+    /// See `observer::LoggerObserver`.
     pub logger: Arc<dyn Fn(&str) -> () + Sync + Send>,
+    /// Get the events of the build. See `observer::BuildObserver`.
+    pub observers: Vec<Arc<dyn BuildObserver>>,
     targets_scheduler: scheduler::TargetScheduler,
+    children: Children,
 }
 
 impl Executer {
@@ -23,81 +126,616 @@ impl Executer {
         let arc = Arc::new(targets);
         Executer {
             thread_count: count,
+            keep_going: false,
+            always_execute: false,
+            build_state: None,
+            explain: false,
+            capture_output: false,
+            caches: Vec::new(),
+            jobserver: None,
+            pools: AHashMap::new(),
+            throttle: Throttle::default(),
+            grace_period: Duration::from_secs(5),
             targets_scheduler: TargetScheduler::new(arc.clone()),
             logger: Arc::new(|_| -> () { () }),
+            observers: Vec::new(),
             all_targets: arc,
+            children: Children::default(),
+        }
+    }
+
+    /// Check if a target need be executed.
+    /// Use the build state if there is one, otherwise the modified time.
+    fn check(&self, target: &Target) -> StateCheck {
+        let mut check = match &self.build_state {
+            Some(state) => state.check(target, &self.all_targets),
+            None => StateCheck {
+                staleness: up_to_date::find_staleness(target, &self.all_targets),
+                record: None,
+            },
+        };
+
+        if self.always_execute && check.staleness.is_none() {
+            check.staleness = Some(Staleness::Forced);
+        }
+
+        check
+    }
+
+    /// Get the cache key of a target if it can be cached.
+    ///
+    /// A target without outputs or with a missing input is not cached.
+    /// Neither is a forced one, because it must be executed,
+    /// nor one with a depfile, because its inputs are unknown before it is executed.
+    fn cache_key(
+        &self,
+        target: &Target,
+        record: Option<&TargetRecord>,
+        staleness: &Staleness,
+    ) -> Option<String> {
+        if self.caches.is_empty() || target.commands.iter().any(|c| c.command.depfile.is_some()) {
+            return None;
+        }
+
+        match staleness {
+            Staleness::NoOutputs | Staleness::Forced | Staleness::MissingInput(_) => None,
+            _ => Some(match record {
+                Some(record) => record.key(),
+                None => TargetRecord::of(target, &self.all_targets).key(),
+            }),
+        }
+    }
+
+    /// Restore the outputs of a target from the first cache that has them.
+    /// The caches before that one get the outputs too.
+    fn restore(
+        &self,
+        observer: &dyn BuildObserver,
+        worker: u64,
+        target: &Target,
+        key: &str,
+    ) -> bool {
+        for (index, cache) in self.caches.iter().enumerate() {
+            match cache.restore(key, target) {
+                Ok(true) => {
+                    self.store(observer, worker, target, key, &self.caches[..index]);
+                    return true;
+                }
+                Ok(false) => {}
+                Err(err) => observer.warning(
+                    worker,
+                    &target.name,
+                    format!("Failed to restore {} from the cache:{}", target.name, err).as_str(),
+                ),
+            }
+        }
+
+        false
+    }
+
+    /// Store the outputs of a target into the caches.
+    /// A cache that fails is only a warning.
+    fn store(
+        &self,
+        observer: &dyn BuildObserver,
+        worker: u64,
+        target: &Target,
+        key: &str,
+        caches: &[Arc<dyn OutputCache>],
+    ) {
+        for cache in caches {
+            if let Err(err) = cache.store(key, target) {
+                observer.warning(
+                    worker,
+                    &target.name,
+                    format!("Failed to store {} into the cache:{}", target.name, err).as_str(),
+                );
+            }
+        }
+    }
+
+    /// Wait for a job slot shared with the other programs. None if there is no jobserver.
+    ///
+    /// The target fails if there is no slot, like when the jobserver was closed,
+    /// rather than running more jobs than the jobserver allows.
+    fn acquire_slot(&self, target: &Target) -> Result<Option<Token<'_>>, RuntimeError> {
+        let jobserver = match &self.jobserver {
+            Some(jobserver) => jobserver,
+            None => return Ok(None),
+        };
+
+        jobserver
+            .acquire()
+            .map(Some)
+            .map_err(|err| match interrupt::received() {
+                Some(signal) if err.kind() == io::ErrorKind::Interrupted => {
+                    interrupted(target, signal)
+                }
+                _ => RuntimeError {
+                    source: Some(Arc::new(err) as Arc<dyn std::error::Error>),
+                    command: None,
+                    reason: Some(format!(
+                        "can not get a job slot from the jobserver for {}",
+                        target.name
+                    )),
+                    output: None,
+                },
+            })
+    }
+
+    /// How to execute the commands of a target.
+    ///
+    /// A command gets its own process group only when the interrupts are handled,
+    /// otherwise Ctrl-C would not reach it. See `interrupt`.
+    fn run_options(&self, console: bool) -> RunOptions {
+        RunOptions {
+            capture: self.capture_output && !console,
+            environments: match &self.jobserver {
+                Some(jobserver) => jobserver.environments(),
+                None => Vec::new(),
+            },
+            process_group: !console && interrupt::is_installed(),
         }
     }
 
+    /// Execute the commands of a target and stop at the first failed one.
+    /// Return the captured output and the error.
+    ///
+    /// A target fails if remake is interrupted when it executes,
+    /// because its outputs may be written partly.
+    fn run_commands(
+        &self,
+        observer: &dyn BuildObserver,
+        worker: u64,
+        target: &Target,
+    ) -> (Vec<u8>, Option<RuntimeError>) {
+        let mut output = Vec::new();
+
+        // the console pool uses the terminal
+        let console = target.pool.as_deref() == Some(CONSOLE_POOL);
+
+        let options = self.run_options(console);
+
+        for command in target.commands.iter() {
+            let command = &command.command;
+
+            if let Some(signal) = interrupt::received() {
+                return (output, Some(interrupted(target, signal)));
+            }
+
+            let run = command.run_with(
+                &options,
+                &mut |pid| {
+                    self.children.add(pid, options.process_group);
+                    observer.command_spawned(worker, &target.name, command, pid)
+                },
+                // before it is reaped, so the pid is never signaled after it is reused
+                &mut |pid| self.children.remove(pid),
+            );
+            observer.command_exited(worker, &target.name, command, &run);
+
+            match run.result {
+                Ok(captured) => output.extend(captured),
+                Err(err) => {
+                    if let Some(captured) = &err.output {
+                        output.extend(captured.as_bytes());
+                    }
+                    return (output, Some(err));
+                }
+            }
+        }
+
+        // the last command may ignore the signal or errors
+        if let Some(signal) = interrupt::received() {
+            return (output, Some(interrupted(target, signal)));
+        }
+
+        (output, None)
+    }
+
+    /// Remove the outputs that an interrupted target created or modified, like make does.
+    ///
+    /// They may be written partly, and their new modified time would make the target
+    /// up to date next time. The outputs that the commands did not touch are kept.
+    fn remove_changed_outputs(
+        &self,
+        observer: &dyn BuildObserver,
+        worker: u64,
+        target: &Target,
+        before: &[Option<SystemTime>],
+    ) {
+        for output in up_to_date::changed_outputs(target, before) {
+            let message = match fs::remove_file(output) {
+                Ok(()) => format!(
+                    "Removed the output `{}` of the interrupted target {}",
+                    output, target.name
+                ),
+                Err(err) => format!(
+                    "Failed to remove the output `{}` of the interrupted target {}:{}",
+                    output, target.name, err
+                ),
+            };
+            observer.warning(worker, &target.name, message.as_str());
+        }
+    }
+
+    /// Wait for an interrupt until the workers exit. See `interrupt`.
+    ///
+    /// Then no new target starts, and the signal is forwarded to the running commands.
+    /// The commands still running after `grace_period` or a second interrupt are killed.
+    fn watch_interrupts(&self, watcher: &Watcher) {
+        match watcher.wait(None) {
+            Ok(Wake::Interrupted) => {}
+            Ok(_) => return,
+            Err(err) => {
+                (self.logger)(format!("Failed to watch the interrupts:{}", err).as_str());
+                return;
+            }
+        }
+
+        let signal = match interrupt::received() {
+            Some(signal) => signal,
+            None => return,
+        };
+
+        (self.logger)(
+            format!(
+                "Interrupted by the signal {}, waiting for the running commands",
+                signal
+            )
+            .as_str(),
+        );
+        self.targets_scheduler.report_error();
+        self.children.signal(signal);
+
+        let deadline = Instant::now() + self.grace_period;
+
+        // a second interrupt kills them at once
+        while interrupt::count() < 2 {
+            match watcher.wait(Some(deadline.saturating_duration_since(Instant::now()))) {
+                Ok(Wake::Interrupted) => {}
+                // the commands exited, so did the workers
+                Ok(Wake::Stopped) => return,
+                Ok(Wake::TimedOut) | Err(_) => break,
+            }
+        }
+
+        (self.logger)("Killing the running commands");
+        self.children.signal(interrupt::KILL_SIGNAL);
+    }
+
+    /// The loop of a worker thread.
+    fn work(
+        &self,
+        observer: &dyn BuildObserver,
+        errors: &Mutex<Vec<String>>,
+        durations: &Mutex<AHashMap<Arc<String>, Duration>>,
+    ) {
+        let scheduler = &self.targets_scheduler;
+        let worker = thread::current().id().as_u64().get();
+
+        let took = |target: &Target, begin: Instant| {
+            durations
+                .lock()
+                .unwrap()
+                .insert(target.name.clone(), begin.elapsed());
+        };
+
+        let held_back = |target: &str, reason: &str| {
+            observer.warning(
+                worker,
+                target,
+                format!("Hold back {} because {}", target, reason).as_str(),
+            )
+        };
+
+        while let Some(target) = scheduler.get_next_target(&held_back) {
+            let begin = Instant::now();
+            observer.target_scheduled(worker, &target.name);
+
+            let check = self.check(&target);
+
+            let staleness = match check.staleness {
+                Some(staleness) => staleness,
+                None => {
+                    took(&target, begin);
+                    scheduler.up_to_date_target(target.name.clone());
+                    observer.target_finished(worker, &target.name, TargetState::UpToDate);
+                    continue;
+                }
+            };
+
+            observer.target_started(worker, &target.name, &staleness);
+
+            let cached = self.cache_key(&target, check.record.as_ref(), &staleness);
+
+            if let Some(key) = &cached {
+                if self.restore(observer, worker, &target, key) {
+                    if let (Some(state), Some(record)) = (&self.build_state, check.record) {
+                        state.record(&target.name, record);
+                    }
+                    took(&target, begin);
+                    scheduler.restored_target(target.name.clone());
+                    observer.target_finished(worker, &target.name, TargetState::Restored);
+                    continue;
+                }
+            }
+
+            // to find the outputs written partly if interrupted
+            let before = up_to_date::output_times(&target);
+
+            // stop at the first failed command
+            let (output, failed) = match self.acquire_slot(&target) {
+                Ok(token) => {
+                    let ran = self.run_commands(observer, worker, &target);
+                    drop(token);
+                    ran
+                }
+                Err(err) => (Vec::new(), Some(err)),
+            };
+
+            if !output.is_empty() {
+                observer.target_output(worker, &target.name, &output);
+            }
+
+            match failed {
+                None => {
+                    if let Some(key) = &cached {
+                        self.store(observer, worker, &target, key, &self.caches);
+                    }
+                    if let Some(state) = &self.build_state {
+                        state.record_duration(&target.name, begin.elapsed());
+                    }
+                    if let (Some(state), Some(mut record)) = (&self.build_state, check.record) {
+                        match record.read_depfiles(&target) {
+                            Ok(()) => state.record(&target.name, record),
+                            Err(err) => {
+                                // execute it again next time
+                                state.forget(&target.name);
+
+                                observer.warning(
+                                    worker,
+                                    &target.name,
+                                    format!(
+                                        "Failed to read the depfiles of {}:{}",
+                                        target.name, err
+                                    )
+                                    .as_str(),
+                                );
+                            }
+                        }
+                    }
+                    took(&target, begin);
+                    scheduler.done_target(target.name.clone());
+                    observer.target_finished(worker, &target.name, TargetState::Succeeded);
+                }
+                Some(err) => {
+                    // a failed target is never done, so its dependents never run.
+                    // No new target starts unless keep going, but the begun targets finish.
+                    if let Some(state) = &self.build_state {
+                        state.forget(&target.name);
+                    }
+                    if interrupt::received().is_some() {
+                        self.remove_changed_outputs(observer, worker, &target, &before);
+                    }
+                    took(&target, begin);
+                    scheduler.fail_target(target.name.clone());
+                    errors.lock().unwrap().push(err.to_string());
+                    observer.target_failed(worker, &target.name, &err);
+                }
+            }
+        }
+
+        observer.worker_exited(worker);
+    }
+
     /// Parse the dependences of the targets and execute them at a sequence.
-    pub fn execute(&mut self, targets: &Vec<String>) -> spin::Mutex<Vec<String>> {
+    ///
+    /// The targets are checked by the `graph` module first.
+    /// Nothing is executed if there is a problem.
+    ///
+    /// As docs/standard_1.md says, a target stops at its first failed command and generates a error.
+    /// Then no new target is executed, and this returns after the executing targets finish.
+    /// But if `keep_going` is set, only the targets that depend on the failed target are skipped.
+    ///
+    /// A target is not executed if it is up to date, unless `always_execute` is set.
+    /// See docs/standard_2.md. With a `build_state` the hashes decide instead,
+    /// and the state records the targets executed successfully. Save it after this returns.
+    ///
+    /// With `caches`, a target that is not up to date is restored from them if possible.
+    ///
+    /// At most the depth of a pool targets of it execute at the same time. See docs/standard_4.md.
+    ///
+    /// After `interrupt::install`, an interrupt stops the build like a failed target,
+    /// and the targets executing fail. See docs/interrupt.md.
+    ///
+    /// The events are passed to the `logger` and the `observers`.
+    pub fn execute(&mut self, targets: &Vec<String>) -> BuildReport {
+        let begin = Instant::now();
+
+        let mut observers: Vec<Arc<dyn BuildObserver>> = vec![Arc::new(LoggerObserver {
+            logger: self.logger.clone(),
+            explain: self.explain,
+        })];
+        observers.extend(self.observers.iter().cloned());
+        let observer = Observers(observers);
+        observer.build_started(targets);
+
+        // check targets
+        let mut problems = graph::check_requested(&self.all_targets, targets);
+        problems.append(&mut graph::check_targets(&self.all_targets));
+        problems.append(&mut graph::check_pools(&self.all_targets, &self.pools));
+
+        if !problems.is_empty() {
+            let report = BuildReport::from_errors(problems.iter().map(|p| p.to_string()).collect());
+            observer.build_finished(&report, begin.elapsed());
+            return report;
+        }
+
+        self.targets_scheduler.set_keep_going(self.keep_going);
+        self.targets_scheduler.set_pools(&self.pools);
+        self.targets_scheduler.set_throttle(self.throttle.clone());
+
         // resolve targets
         for target in targets {
             self.targets_scheduler.target(target);
         }
-        let errors: spin::Mutex<Vec<String>> =
-            spin::Mutex::new(Vec::with_capacity(self.thread_count as usize));
-        let dur = Duration::from_millis(10);
+
+        // start the long chains first, see docs/standard_2.md
+        if let Some(state) = &self.build_state {
+            self.targets_scheduler
+                .prioritize(&|target| state.duration(target));
+        }
+        let errors: Mutex<Vec<String>> = Mutex::new(Vec::with_capacity(self.thread_count as usize));
+        let durations: Mutex<AHashMap<Arc<String>, Duration>> = Mutex::new(AHashMap::new());
+        // only if the signals are handled, see `interrupt::install`
+        let watcher = if interrupt::is_installed() {
+            match Watcher::new() {
+                Ok(watcher) => Some(watcher),
+                Err(err) => {
+                    (self.logger)(format!("Failed to watch the interrupts:{}", err).as_str());
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let executer: &Executer = self;
 
         // begin to work
         thread::scope(|s| {
             let mut threads: Vec<thread::ScopedJoinHandle<_>> = Vec::new();
-            let scheduler = &self.targets_scheduler;
-
-            for _ in 0..self.thread_count {
-                let j = s.spawn(|| {
-                    let id = thread::current().id();
-                    loop {
-                        let target = scheduler.get_next_target();
-
-                        match target {
-                            None => {
-                                return {
-                                    (*self.logger)(format!("Thread {} Exit", id.as_u64()).as_str());
-                                }
-                            }
-                            Some(target) => {
-                                for command in target.commands.iter() {
-                                    let run = command.run();
-
-                                    if run.is_err() {
-                                        scheduler.report_error();
-                                        errors.lock().push(run.err().unwrap().to_string());
-                                    }
-                                }
-                                scheduler.done_target(target.name.clone());
-
-                                (*self.logger)(
-                                    format!("Thread {} Executed {}", id.as_u64(), target.name)
-                                        .as_str(),
-                                );
-                            }
-                        }
-                    }
-                });
 
-                threads.push(j);
+            for _ in 0..self.thread_count.max(1) {
+                threads.push(s.spawn(|| self.work(&observer, &errors, &durations)));
             }
 
-            loop {
-                let mut all_finished = true;
+            let watching = watcher
+                .as_ref()
+                .map(|watcher| s.spawn(move || executer.watch_interrupts(watcher)));
 
-                for t in &threads {
-                    if !t.is_finished() {
-                        all_finished = false;
-                        break;
-                    }
-                }
-
-                if all_finished {
-                    return;
-                }
+            // the workers exit when the scheduler has nothing more to give
+            for t in threads {
+                t.join().unwrap();
+            }
 
-                thread::sleep(dur);
+            if let (Some(watcher), Some(watching)) = (&watcher, watching) {
+                watcher.stop();
+                watching.join().unwrap();
             }
         });
 
-        // return
-        return errors;
+        // report
+        let mut report = BuildReport::from_errors(errors.into_inner().unwrap());
+        report.durations = durations.into_inner().unwrap();
+        report.interrupted = interrupt::received();
+
+        // the build may be stopped between targets, when no target fails
+        if let Some(signal) = report.interrupted {
+            report.errors.push(format!(
+                "the build was interrupted by the signal {}",
+                signal
+            ));
+        }
+
+        for (name, state) in self.targets_scheduler.states() {
+            match state {
+                TargetState::Succeeded => report.succeeded.push(name),
+                TargetState::UpToDate => report.up_to_date.push(name),
+                TargetState::Restored => report.restored.push(name),
+                TargetState::Failed => report.failed.push(name),
+                TargetState::Skipped => report.skipped.push(name),
+                _ => {}
+            }
+        }
+
+        report.succeeded.sort_unstable();
+        report.up_to_date.sort_unstable();
+        report.restored.sort_unstable();
+        report.failed.sort_unstable();
+        report.skipped.sort_unstable();
+
+        for target in report.skipped.iter() {
+            observer.target_skipped(target);
+        }
+        observer.build_finished(&report, begin.elapsed());
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::builder::{GraphBuilder, TargetBuilder};
+
+    struct Warnings(Mutex<Vec<String>>);
+
+    impl BuildObserver for Warnings {
+        fn warning(&self, _worker: u64, _target: &str, message: &str) {
+            self.0.lock().unwrap().push(String::from(message));
+        }
+    }
+
+    #[test]
+    fn process_group_only_with_the_handler() {
+        let executer = Executer::new(1, AHashMap::new());
+
+        // Ctrl-C reaches the commands when remake does not handle it
+        assert!(!executer.run_options(false).process_group);
+
+        interrupt::install().unwrap();
+        assert!(executer.run_options(false).process_group);
+        // the console pool uses the terminal
+        assert!(!executer.run_options(true).process_group);
+    }
+
+    #[test]
+    fn remove_the_outputs_written_when_interrupted() {
+        let directory =
+            std::env::temp_dir().join(format!("remake-executer-test-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| String::from(directory.join(name).to_str().unwrap());
+        let (kept, written, created) = (path("kept"), path("written"), path("created"));
+
+        let mut graph = GraphBuilder::new();
+        graph
+            .target(
+                TargetBuilder::new("partly")
+                    .output(&kept)
+                    .output(&written)
+                    .output(&created),
+            )
+            .unwrap();
+        let remake = graph.build().unwrap();
+        let target = remake.targets.values().next().unwrap().clone();
+
+        fs::write(&kept, "old").unwrap();
+        fs::write(&written, "old").unwrap();
+        // older than the next write on any file system
+        let old = SystemTime::now() - Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&written)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let before = up_to_date::output_times(&target);
+        fs::write(&written, "partly").unwrap();
+        fs::write(&created, "partly").unwrap();
+
+        let executer = Executer::new(1, remake.targets);
+        let warnings = Warnings(Mutex::new(Vec::new()));
+        executer.remove_changed_outputs(&warnings, 1, &target, &before);
+
+        let exists = [&kept, &written, &created].map(|p| fs::metadata(p).is_ok());
+        let staleness = up_to_date::find_staleness(&target, &executer.all_targets);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(exists, [true, false, false]);
+        assert_eq!(warnings.0.lock().unwrap().len(), 2);
+        // so it is executed next time, without a build state too
+        assert_eq!(staleness, Some(Staleness::MissingOutput(written)));
     }
 }
@@ -0,0 +1,182 @@
+//! Observe a build by typed callbacks instead of parsing the log.
+//!
+//! The `worker` of a callback is the id of the worker thread that calls it.
+//! The callbacks are called from the worker threads, so an observer must be `Sync`.
+
+use super::scheduler::TargetState;
+use super::up_to_date::Staleness;
+use super::BuildReport;
+use crate::errors::RuntimeError;
+use crate::format::{Command, CommandRun};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Get the events of a build. All the callbacks do nothing by default.
+///
+/// A build begins with `build_started` and ends with `build_finished`.
+/// For a target, a worker calls `target_scheduled` first. Then either `target_finished` if it
+/// is up to date, or `target_started`, the command callbacks, `target_output`,
+/// and `target_finished` or `target_failed`.
+#[allow(unused_variables)]
+pub trait BuildObserver: Sync + Send {
+    /// The build began, before the targets are checked. `targets` are the requested ones.
+    fn build_started(&self, targets: &[String]) {}
+
+    /// A worker got the target from the scheduler.
+    fn target_scheduled(&self, worker: u64, target: &str) {}
+
+    /// The target is not up to date and will be executed, or restored from the cache.
+    fn target_started(&self, worker: u64, target: &str, staleness: &Staleness) {}
+
+    fn command_spawned(&self, worker: u64, target: &str, command: &Command, pid: u32) {}
+
+    /// `run.result` is the output or the error of the command.
+    fn command_exited(&self, worker: u64, target: &str, command: &Command, run: &CommandRun) {}
+
+    /// The captured output of all the commands of the target. Never empty.
+    fn target_output(&self, worker: u64, target: &str, output: &[u8]) {}
+
+    /// The state is `Succeeded`, `UpToDate` or `Restored`.
+    fn target_finished(&self, worker: u64, target: &str, state: TargetState) {}
+
+    fn target_failed(&self, worker: u64, target: &str, error: &RuntimeError) {}
+
+    /// The target was not executed because some of its dependences failed.
+    /// Called after all the workers exited.
+    fn target_skipped(&self, target: &str) {}
+
+    /// Something went wrong but the build goes on, like a cache that can not be read.
+    fn warning(&self, worker: u64, target: &str, message: &str) {}
+
+    fn worker_exited(&self, worker: u64) {}
+
+    fn build_finished(&self, report: &BuildReport, duration: Duration) {}
+}
+
+/// Pass the events to every observer in order.
+pub struct Observers(pub Vec<Arc<dyn BuildObserver>>);
+
+impl BuildObserver for Observers {
+    fn build_started(&self, targets: &[String]) {
+        self.0.iter().for_each(|o| o.build_started(targets));
+    }
+
+    fn target_scheduled(&self, worker: u64, target: &str) {
+        self.0
+            .iter()
+            .for_each(|o| o.target_scheduled(worker, target));
+    }
+
+    fn target_started(&self, worker: u64, target: &str, staleness: &Staleness) {
+        self.0
+            .iter()
+            .for_each(|o| o.target_started(worker, target, staleness));
+    }
+
+    fn command_spawned(&self, worker: u64, target: &str, command: &Command, pid: u32) {
+        self.0
+            .iter()
+            .for_each(|o| o.command_spawned(worker, target, command, pid));
+    }
+
+    fn command_exited(&self, worker: u64, target: &str, command: &Command, run: &CommandRun) {
+        self.0
+            .iter()
+            .for_each(|o| o.command_exited(worker, target, command, run));
+    }
+
+    fn target_output(&self, worker: u64, target: &str, output: &[u8]) {
+        self.0
+            .iter()
+            .for_each(|o| o.target_output(worker, target, output));
+    }
+
+    fn target_finished(&self, worker: u64, target: &str, state: TargetState) {
+        self.0
+            .iter()
+            .for_each(|o| o.target_finished(worker, target, state));
+    }
+
+    fn target_failed(&self, worker: u64, target: &str, error: &RuntimeError) {
+        self.0
+            .iter()
+            .for_each(|o| o.target_failed(worker, target, error));
+    }
+
+    fn target_skipped(&self, target: &str) {
+        self.0.iter().for_each(|o| o.target_skipped(target));
+    }
+
+    fn warning(&self, worker: u64, target: &str, message: &str) {
+        self.0
+            .iter()
+            .for_each(|o| o.warning(worker, target, message));
+    }
+
+    fn worker_exited(&self, worker: u64) {
+        self.0.iter().for_each(|o| o.worker_exited(worker));
+    }
+
+    fn build_finished(&self, report: &BuildReport, duration: Duration) {
+        self.0
+            .iter()
+            .for_each(|o| o.build_finished(report, duration));
+    }
+}
+
+/// Print the events as lines of text, like `Thread 3 Executed foo`.
+/// This is how `Executer::logger` works.
+pub struct LoggerObserver {
+    pub logger: Arc<dyn Fn(&str) + Sync + Send>,
+    /// If true, log why a target is executed.
+    pub explain: bool,
+}
+
+impl BuildObserver for LoggerObserver {
+    fn target_started(&self, worker: u64, target: &str, staleness: &Staleness) {
+        if self.explain {
+            (*self.logger)(format!("Thread {} Stale {}: {}", worker, target, staleness).as_str());
+        }
+    }
+
+    /// Every line is prefixed with the target name.
+    fn target_output(&self, _worker: u64, target: &str, output: &[u8]) {
+        let text = String::from_utf8_lossy(output);
+        let mut message = String::with_capacity(text.len() + 64);
+
+        for line in text.trim_end_matches(['\n', '\r']).lines() {
+            if !message.is_empty() {
+                message.push('\n');
+            }
+            message.push('[');
+            message.push_str(target);
+            message.push_str("] ");
+            message.push_str(line);
+        }
+
+        // one call, so the lines of a target are together
+        (*self.logger)(message.as_str());
+    }
+
+    fn target_finished(&self, worker: u64, target: &str, state: TargetState) {
+        let action = match state {
+            TargetState::UpToDate => "Up-to-date",
+            TargetState::Restored => "Restored",
+            _ => "Executed",
+        };
+
+        (*self.logger)(format!("Thread {} {} {}", worker, action, target).as_str());
+    }
+
+    fn target_failed(&self, worker: u64, target: &str, _error: &RuntimeError) {
+        (*self.logger)(format!("Thread {} Failed {}", worker, target).as_str());
+    }
+
+    fn warning(&self, worker: u64, _target: &str, message: &str) {
+        (*self.logger)(format!("Thread {} {}", worker, message).as_str());
+    }
+
+    fn worker_exited(&self, worker: u64) {
+        (*self.logger)(format!("Thread {} Exit", worker).as_str());
+    }
+}
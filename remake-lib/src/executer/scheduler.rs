@@ -1,116 +1,669 @@
+use super::throttle::{Throttle, THROTTLE_INTERVAL};
+use crate::format::definition::CONSOLE_POOL;
 use crate::format::Target;
 use ahash::AHashMap;
 use ahash::AHashSet;
-use spin;
-use spin::MutexGuard;
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-/// The is a schedler
+/// The state of a target in the scheduler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetState {
+    /// Some dependences have not been executed.
+    Waiting,
+    /// In the ready queue.
+    Ready,
+    /// Got by a worker.
+    Running,
+    Succeeded,
+    /// The target was not executed because its outputs were up to date.
+    UpToDate,
+    /// The outputs of the target were restored from the cache.
+    Restored,
+    Failed,
+    /// A dependence failed, so the target will never be executed.
+    Skipped,
+}
+
+/// A target in the dependence graph.
+struct Node {
+    target: Arc<Target>,
+    state: TargetState,
+    /// How many dependences have not been executed.
+    pending: usize,
+    /// The targets that depend on this one.
+    dependents: Vec<usize>,
+    /// How long the longest chain from this target to the end takes. See `prioritize`.
+    priority: Duration,
+    /// The index of its pool.
+    pool: Option<usize>,
+}
+
+/// At most `depth` targets of a pool are running.
+struct Pool {
+    depth: u64,
+    running: u64,
+    /// The ready targets that wait for the running ones of the pool.
+    delayed: BinaryHeap<(Duration, Reverse<usize>)>,
+}
+
+/// The dependence graph of the marked targets.
+struct Graph {
+    nodes: Vec<Node>,
+    indexes: AHashMap<Arc<String>, usize>,
+    /// The targets whose dependences have all been executed.
+    /// The one with the highest priority is got first, then the one added first.
+    ready: BinaryHeap<(Duration, Reverse<usize>)>,
+    /// How many targets were got but not done.
+    running: usize,
+    pools: Vec<Pool>,
+    pool_indexes: AHashMap<String, usize>,
+    throttle: Throttle,
+    /// When the throttle was checked last time, and why it held back the targets.
+    throttled: Option<(Instant, Option<String>)>,
+}
+
+impl Graph {
+    /// A target of a pool finished, so a delayed one can be got.
+    fn leave_pool(&mut self, index: usize) {
+        if let Some(pool) = self.nodes[index].pool {
+            let pool = &mut self.pools[pool];
+            pool.running -= 1;
+
+            if let Some(entry) = pool.delayed.pop() {
+                self.ready.push(entry);
+            }
+        }
+    }
+}
+
+/// The is a schedler.
+///
+/// The marked targets and their dependences are put into a graph once.
+/// Every target counts its dependences that have not been executed,
+/// and is put into the ready queue when the count drops to zero.
+/// So every target is scheduled exactly once.
+///
+/// The ready targets on the longest chains are got first if the durations are known.
+/// A ready target waits if its pool is full.
+///
+/// The workers block on a condition variable while there is nothing to do,
+/// and are woken up when a target is done or an error is reported.
 pub struct TargetScheduler {
     all_targets: Arc<AHashMap<Arc<String>, Arc<Target>>>,
-    todo_targets: spin::Mutex<VecDeque<Arc<Target>>>,
-    unresolved_targets: spin::Mutex<Vec<Arc<Target>>>,
-    done_targets: spin::Mutex<AHashSet<Arc<String>>>,
+    graph: Mutex<Graph>,
+    changed: Condvar,
     done: AtomicBool,
     error: AtomicBool,
+    keep_going: AtomicBool,
 }
 
 impl TargetScheduler {
-    /// Resolve a target.
-    /// This will deal with wheir dependences.
-    fn resolve_target(
-        &self,
-        target: Arc<Target>,
-        todo_targets: &mut MutexGuard<VecDeque<Arc<Target>>>,
-        unresolved_targets: &mut MutexGuard<Vec<Arc<Target>>>,
-    ) {
-        // check
-        let deps = &target.dependences;
-        todo_targets.push_back(target.clone());
-        for item in deps.iter() {
-            unresolved_targets.push(self.all_targets.get(item).unwrap().clone());
-        }
-    }
-
-    /// Get a target that has no dependence or all its dependences have executed.
-    fn get_runable_target(
-        &self,
-        todo_targets: &MutexGuard<VecDeque<Arc<Target>>>,
-        done_targets: &MutexGuard<AHashSet<Arc<String>>>,
-    ) -> Option<Arc<Target>> {
-        for todo in todo_targets.iter().rev() {
-            let mut is_done = true;
-            for dependence in todo.dependences.iter() {
-                if !(*done_targets).contains(dependence) {
-                    is_done = false;
-                    break;
+    /// Add a target and its dependences into the graph.
+    /// The dependences are added before their dependents.
+    /// A dependence that is not defined is ignored. Use `graph::check_targets` to find them.
+    fn resolve_target(&self, graph: &mut Graph, target: &Arc<Target>) {
+        if graph.indexes.contains_key(&target.name) {
+            return;
+        }
+
+        // (target, whether its dependences were pushed)
+        let mut stack: Vec<(Arc<Target>, bool)> = vec![(target.clone(), false)];
+        // the targets whose dependences were pushed but are not in the graph yet
+        let mut visiting: AHashSet<Arc<String>> = AHashSet::new();
+
+        while let Some((target, expanded)) = stack.pop() {
+            if graph.indexes.contains_key(&target.name) {
+                continue;
+            }
+
+            if !expanded {
+                if visiting.contains(&target.name) {
+                    // a cycle. `graph::check_targets` reports it
+                    continue;
+                }
+
+                visiting.insert(target.name.clone());
+                stack.push((target.clone(), true));
+
+                for dependence in target.dependences.iter() {
+                    if let Some(dependence) = self.all_targets.get(dependence) {
+                        if !graph.indexes.contains_key(&dependence.name) {
+                            stack.push((dependence.clone(), false));
+                        }
+                    }
                 }
+                continue;
             }
-            if is_done {
-                return Some(todo.clone());
+
+            // all the dependences are in the graph now
+            let index = graph.nodes.len();
+            let mut dependences: AHashSet<usize> = AHashSet::new();
+
+            for dependence in target.dependences.iter() {
+                // the dependences in a cycle are not in the graph, skip them
+                if let Some(dependence) = graph.indexes.get(dependence) {
+                    dependences.insert(*dependence);
+                }
+            }
+
+            for dependence in dependences.iter() {
+                graph.nodes[*dependence].dependents.push(index);
+            }
+
+            // a pool that is not defined does not limit. `graph::check_pools` reports it
+            let pool = target
+                .pool
+                .as_ref()
+                .and_then(|pool| graph.pool_indexes.get(pool))
+                .copied();
+
+            visiting.remove(&target.name);
+            graph.indexes.insert(target.name.clone(), index);
+            graph.nodes.push(Node {
+                target,
+                state: TargetState::Waiting,
+                pending: dependences.len(),
+                dependents: Vec::new(),
+                priority: Duration::ZERO,
+                pool,
+            });
+
+            if dependences.is_empty() {
+                graph.nodes[index].state = TargetState::Ready;
+                graph.ready.push((Duration::ZERO, Reverse(index)));
             }
         }
-        return None;
     }
 
     /// Create a new scheduler from targets
     pub fn new(all_targets: Arc<AHashMap<Arc<String>, Arc<Target>>>) -> TargetScheduler {
         TargetScheduler {
             all_targets,
-            todo_targets: spin::Mutex::new(VecDeque::with_capacity(32)),
-            unresolved_targets: spin::Mutex::new(Vec::with_capacity(32)),
-            done_targets: spin::Mutex::new(AHashSet::with_capacity(32)),
+            graph: Mutex::new(Graph {
+                nodes: Vec::with_capacity(32),
+                indexes: AHashMap::with_capacity(32),
+                ready: BinaryHeap::with_capacity(32),
+                running: 0,
+                pools: vec![Pool {
+                    depth: 1,
+                    running: 0,
+                    delayed: BinaryHeap::new(),
+                }],
+                pool_indexes: AHashMap::from_iter([(String::from(CONSOLE_POOL), 0)]),
+                throttle: Throttle::default(),
+                throttled: None,
+            }),
+            changed: Condvar::new(),
             done: AtomicBool::new(false),
             error: AtomicBool::new(false),
+            keep_going: AtomicBool::new(false),
         }
     }
 
-    /// Get the next target that will be executed.
-    pub fn get_next_target(&self) -> Option<Arc<Target>> {
-        if self.is_done() {
-            return None;
+    /// If true, a failed target only stops its dependents. Other targets keep executing.
+    /// Otherwise a failed target stops the scheduler.
+    pub fn set_keep_going(&self, keep_going: bool) {
+        self.keep_going.store(keep_going, Ordering::SeqCst);
+    }
+
+    /// Stop giving new targets while the system is busy. See `get_next_target`.
+    pub fn set_throttle(&self, throttle: Throttle) {
+        self.graph.lock().unwrap().throttle = throttle;
+    }
+
+    /// Set the depths of the pools. The `console` pool is always there.
+    /// The pools set before are replaced. Call this before marking the targets.
+    pub fn set_pools(&self, pools: &AHashMap<String, u64>) {
+        let mut graph = self.graph.lock().unwrap();
+
+        // the console pool is the first one
+        graph.pools.truncate(1);
+        graph.pool_indexes.retain(|name, _| name == CONSOLE_POOL);
+
+        for (name, depth) in pools.iter() {
+            if name == CONSOLE_POOL {
+                continue;
+            }
+
+            let index = graph.pools.len();
+            graph.pools.push(Pool {
+                depth: *depth,
+                running: 0,
+                delayed: BinaryHeap::new(),
+            });
+            graph.pool_indexes.insert(name.clone(), index);
         }
+    }
+
+    /// Get the longest chains first by the durations of the targets, like the last build took.
+    /// A target without a duration is assumed to take the average of the known ones.
+    ///
+    /// The priority of a target is its duration plus the longest priority of its dependents.
+    /// Call this after marking the targets and before getting any.
+    pub fn prioritize(&self, duration: &dyn Fn(&str) -> Option<Duration>) {
+        let mut graph = self.graph.lock().unwrap();
+        let graph = &mut *graph;
+
+        let durations: Vec<Option<Duration>> = graph
+            .nodes
+            .iter()
+            .map(|node| duration(&node.target.name))
+            .collect();
 
-        let mut todos = self.todo_targets.lock();
-        let dones = self.done_targets.lock();
-        let mut got = self.get_runable_target(&todos, &dones);
+        let known: Vec<Duration> = durations.iter().flatten().copied().collect();
+        let average = if known.is_empty() {
+            Duration::ZERO
+        } else {
+            known.iter().sum::<Duration>() / known.len() as u32
+        };
 
-        while got.is_none() {
-            let mut un = self.unresolved_targets.lock();
-            if un.is_empty() {
-                self.done.store(true, Ordering::Relaxed);
+        // the dependences are before their dependents
+        for index in (0..graph.nodes.len()).rev() {
+            let longest = graph.nodes[index]
+                .dependents
+                .iter()
+                .map(|dependent| graph.nodes[*dependent].priority)
+                .max()
+                .unwrap_or(Duration::ZERO);
+
+            graph.nodes[index].priority = durations[index].unwrap_or(average) + longest;
+        }
+
+        let nodes = &graph.nodes;
+        graph.ready = graph
+            .ready
+            .iter()
+            .map(|(_, Reverse(index))| (nodes[*index].priority, Reverse(*index)))
+            .collect();
+    }
+
+    /// Get the next target that will be executed.
+    ///
+    /// Block if no target is ready but some targets are executing,
+    /// because they may make their dependents ready.
+    /// Return None when all the targets were executed or there is a break.
+    ///
+    /// Also block while the system is over the limits of the throttle and some targets are executing.
+    /// The system is checked at most once every `THROTTLE_INTERVAL`, without holding the lock.
+    /// `held_back` is called with the next target and the reason when the targets begin to be held back.
+    /// A target is always given if nothing is executing, so the build never stops.
+    pub fn get_next_target(&self, held_back: &dyn Fn(&str, &str)) -> Option<Arc<Target>> {
+        let mut graph = self.graph.lock().unwrap();
+
+        loop {
+            if self.is_done() {
                 return None;
-            } else {
-                let got = un.pop();
-                self.resolve_target(got.unwrap(), &mut todos, &mut un);
             }
-            got = self.get_runable_target(&todos, &dones);
-        }
 
-        return Some(got.unwrap());
+            if graph.running > 0 && !graph.ready.is_empty() && graph.throttle.is_set() {
+                // how long the last check is still used, and whether it held back the targets
+                let fresh = match &graph.throttled {
+                    Some((checked, held)) if checked.elapsed() < THROTTLE_INTERVAL => {
+                        Some((THROTTLE_INTERVAL - checked.elapsed(), held.is_some()))
+                    }
+                    _ => None,
+                };
+
+                match fresh {
+                    Some((_, false)) => {}
+                    Some((timeout, true)) => {
+                        graph = self.changed.wait_timeout(graph, timeout).unwrap().0;
+                        continue;
+                    }
+                    None => {
+                        // reading /proc may be slow, so the finishing targets never wait for it
+                        let throttle = graph.throttle.clone();
+                        drop(graph);
+                        let held = throttle.check();
+                        graph = self.graph.lock().unwrap();
+
+                        let was_held = matches!(graph.throttled, Some((_, Some(_))));
+                        graph.throttled = Some((Instant::now(), held.clone()));
+
+                        // report once when the targets begin to be held back
+                        if let (Some(reason), false) = (held, was_held) {
+                            if let Some(entry) = graph.ready.peek() {
+                                let target = graph.nodes[entry.1 .0].target.name.clone();
+                                drop(graph);
+                                held_back(&target, &reason);
+                                graph = self.graph.lock().unwrap();
+                            }
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(entry) = graph.ready.pop() {
+                let index = entry.1 .0;
+
+                if let Some(pool) = graph.nodes[index].pool {
+                    let pool = &mut graph.pools[pool];
+
+                    // wait until a running target of the pool finishes
+                    if pool.running >= pool.depth {
+                        pool.delayed.push(entry);
+                        continue;
+                    }
+                    pool.running += 1;
+                }
+
+                graph.running += 1;
+                graph.nodes[index].state = TargetState::Running;
+                return Some(graph.nodes[index].target.clone());
+            }
+
+            if graph.running == 0 {
+                self.done.store(true, Ordering::SeqCst);
+                self.changed.notify_all();
+                return None;
+            }
+
+            graph = self.changed.wait(graph).unwrap();
+        }
     }
 
     pub fn report_error(&self) {
+        // hold the lock so that no worker misses the notification
+        let _graph = self.graph.lock().unwrap();
         self.error.store(true, Ordering::SeqCst);
+        self.changed.notify_all();
     }
 
-    /// Mark a target was executed.
+    /// Mark a target was executed. Its dependents whose dependences are all executed become ready.
     pub fn done_target(&self, target: Arc<String>) {
-        let mut dones = self.done_targets.lock();
-        dones.insert(target);
+        self.finish_target(target, TargetState::Succeeded);
+    }
+
+    /// Mark a target was up to date. It is treated as executed.
+    pub fn up_to_date_target(&self, target: Arc<String>) {
+        self.finish_target(target, TargetState::UpToDate);
+    }
+
+    /// Mark a target was restored from the cache. It is treated as executed.
+    pub fn restored_target(&self, target: Arc<String>) {
+        self.finish_target(target, TargetState::Restored);
+    }
+
+    fn finish_target(&self, target: Arc<String>, state: TargetState) {
+        let mut graph = self.graph.lock().unwrap();
+        let graph = &mut *graph;
+
+        // wake up the waiting workers.
+        // They get the new ready targets, or exit if all the targets were executed.
+        self.changed.notify_all();
+
+        let index = match graph.indexes.get(&target) {
+            Some(index) => *index,
+            None => return,
+        };
+
+        graph.running -= 1;
+        graph.nodes[index].state = state;
+        graph.leave_pool(index);
+
+        for position in 0..graph.nodes[index].dependents.len() {
+            let dependent = graph.nodes[index].dependents[position];
+            let node = &mut graph.nodes[dependent];
+            node.pending -= 1;
+
+            if node.pending == 0 && node.state == TargetState::Waiting {
+                node.state = TargetState::Ready;
+                graph.ready.push((node.priority, Reverse(dependent)));
+            }
+        }
+    }
+
+    /// Mark a target was failed. All the targets that depend on it, directly or not, are skipped.
+    ///
+    /// Stop the scheduler unless it keeps going.
+    pub fn fail_target(&self, target: Arc<String>) {
+        let mut graph = self.graph.lock().unwrap();
+        let graph = &mut *graph;
+
+        if !self.keep_going.load(Ordering::SeqCst) {
+            self.error.store(true, Ordering::SeqCst);
+        }
+        self.changed.notify_all();
+
+        let index = match graph.indexes.get(&target) {
+            Some(index) => *index,
+            None => return,
+        };
+
+        graph.running -= 1;
+        graph.nodes[index].state = TargetState::Failed;
+        graph.leave_pool(index);
+
+        // a dependent is waiting for the failed target, so it is not in the ready queue
+        let mut poisoned = graph.nodes[index].dependents.clone();
+
+        while let Some(dependent) = poisoned.pop() {
+            let node = &mut graph.nodes[dependent];
+
+            if node.state == TargetState::Waiting {
+                node.state = TargetState::Skipped;
+                poisoned.extend(node.dependents.iter());
+            }
+        }
+    }
+
+    /// Get the states of the marked targets and their dependences.
+    pub fn states(&self) -> Vec<(Arc<String>, TargetState)> {
+        self.graph
+            .lock()
+            .unwrap()
+            .nodes
+            .iter()
+            .map(|node| (node.target.name.clone(), node.state))
+            .collect()
     }
 
     /// Detect if all the targets were get. Or there is a break.
     pub fn is_done(&self) -> bool {
-        return self.done.load(Ordering::SeqCst) || self.error.load(Ordering::SeqCst);
+        self.done.load(Ordering::SeqCst) || self.error.load(Ordering::SeqCst)
     }
 
     /// Mark a target that you want to execute.
+    /// A target that is not defined is ignored. Use `graph::check_requested` to find them.
     pub fn target(&self, target: &String) {
-        let t = self.all_targets.get(target);
-        self.unresolved_targets.lock().push(t.unwrap().clone());
+        if let Some(t) = self.all_targets.get(target) {
+            self.resolve_target(&mut self.graph.lock().unwrap(), t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::builder::{GraphBuilder, TargetBuilder};
+    use std::sync::atomic::AtomicU64;
+    use std::thread;
+
+    fn never_held_back(_target: &str, _reason: &str) {}
+
+    fn scheduler(targets: Vec<TargetBuilder>) -> TargetScheduler {
+        let mut graph = GraphBuilder::new();
+        for target in targets {
+            graph.target(target).unwrap();
+        }
+        TargetScheduler::new(Arc::new(graph.build().unwrap().targets))
+    }
+
+    fn state_of(scheduler: &TargetScheduler, name: &str) -> TargetState {
+        scheduler
+            .states()
+            .into_iter()
+            .find(|(target, _)| target.as_str() == name)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn a_diamond_runs_every_target_once_after_its_dependences() {
+        let scheduler = scheduler(vec![
+            TargetBuilder::new("app")
+                .dependence("left")
+                .dependence("right"),
+            TargetBuilder::new("left").dependence("base"),
+            TargetBuilder::new("right").dependence("base"),
+            TargetBuilder::new("base"),
+        ]);
+        scheduler.target(&String::from("app"));
+
+        // (target, whether it finished) in the order of the events
+        let events: Mutex<Vec<(Arc<String>, bool)>> = Mutex::new(Vec::new());
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while let Some(target) = scheduler.get_next_target(&never_held_back) {
+                        events.lock().unwrap().push((target.name.clone(), false));
+                        thread::sleep(Duration::from_millis(10));
+                        events.lock().unwrap().push((target.name.clone(), true));
+                        scheduler.done_target(target.name.clone());
+                    }
+                });
+            }
+        });
+
+        let events = events.into_inner().unwrap();
+        let position = |name: &str, finished: bool| {
+            let found: Vec<usize> = events
+                .iter()
+                .enumerate()
+                .filter(|(_, (target, f))| target.as_str() == name && *f == finished)
+                .map(|(index, _)| index)
+                .collect();
+            assert_eq!(found.len(), 1, "{} is not run once", name);
+            found[0]
+        };
+
+        assert_eq!(events.len(), 8);
+        for (dependent, dependence) in [
+            ("left", "base"),
+            ("right", "base"),
+            ("app", "left"),
+            ("app", "right"),
+        ] {
+            assert!(position(dependence, true) < position(dependent, false));
+        }
+        assert!(scheduler
+            .states()
+            .iter()
+            .all(|(_, state)| *state == TargetState::Succeeded));
+    }
+
+    /// `fail` fails when it is got, the others succeed. Return the targets in the order got.
+    fn run_with_a_failure(keep_going: bool) -> (TargetScheduler, Vec<String>) {
+        let scheduler = scheduler(vec![
+            TargetBuilder::new("grand").dependence("dependent"),
+            TargetBuilder::new("dependent").dependence("fail"),
+            TargetBuilder::new("fail"),
+            TargetBuilder::new("independent"),
+        ]);
+        scheduler.set_keep_going(keep_going);
+        // `fail` is got first
+        scheduler.target(&String::from("grand"));
+        scheduler.target(&String::from("independent"));
+
+        let mut got = Vec::new();
+        while let Some(target) = scheduler.get_next_target(&never_held_back) {
+            got.push(target.name.to_string());
+
+            if target.name.as_str() == "fail" {
+                scheduler.fail_target(target.name.clone());
+            } else {
+                scheduler.done_target(target.name.clone());
+            }
+        }
+
+        (scheduler, got)
+    }
+
+    #[test]
+    fn keep_going_runs_the_independent_targets() {
+        let (scheduler, got) = run_with_a_failure(true);
+
+        assert_eq!(got, ["fail", "independent"]);
+        assert_eq!(state_of(&scheduler, "fail"), TargetState::Failed);
+        assert_eq!(state_of(&scheduler, "independent"), TargetState::Succeeded);
+        // the dependents of a dependent are skipped too
+        assert_eq!(state_of(&scheduler, "dependent"), TargetState::Skipped);
+        assert_eq!(state_of(&scheduler, "grand"), TargetState::Skipped);
+    }
+
+    #[test]
+    fn a_failure_stops_without_keep_going() {
+        let (scheduler, got) = run_with_a_failure(false);
+
+        assert_eq!(got, ["fail"]);
+        assert_eq!(state_of(&scheduler, "independent"), TargetState::Ready);
+        assert_eq!(state_of(&scheduler, "dependent"), TargetState::Skipped);
+    }
+
+    #[test]
+    fn a_pool_never_runs_more_than_its_depth() {
+        let mut all = TargetBuilder::new("all");
+        let mut targets = Vec::new();
+        for index in 0..6 {
+            let (link, console) = (format!("link{}", index), format!("console{}", index));
+            all = all.dependence(&link).dependence(&console);
+            targets.push(TargetBuilder::new(&link).pool("link"));
+            targets.push(TargetBuilder::new(&console).pool(CONSOLE_POOL));
+        }
+        targets.push(all);
+
+        let mut graph = GraphBuilder::new();
+        graph.pool("link", 2).unwrap();
+        for target in targets {
+            graph.target(target).unwrap();
+        }
+        let scheduler = TargetScheduler::new(Arc::new(graph.build().unwrap().targets));
+
+        // the pools set before are replaced
+        scheduler.set_pools(&AHashMap::from_iter([
+            (String::from("link"), 6),
+            (String::from("old"), 1),
+        ]));
+        scheduler.set_pools(&AHashMap::from_iter([(String::from("link"), 2)]));
+        scheduler.target(&String::from("all"));
+
+        // (running, the most running) of the link pool and the console pool
+        let counts = [(); 2].map(|_| (AtomicU64::new(0), AtomicU64::new(0)));
+
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    while let Some(target) = scheduler.get_next_target(&never_held_back) {
+                        let count = match target.pool.as_deref() {
+                            Some("link") => Some(&counts[0]),
+                            Some(CONSOLE_POOL) => Some(&counts[1]),
+                            _ => None,
+                        };
+
+                        if let Some((running, most)) = count {
+                            most.fetch_max(
+                                running.fetch_add(1, Ordering::SeqCst) + 1,
+                                Ordering::SeqCst,
+                            );
+                            thread::sleep(Duration::from_millis(10));
+                            running.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        scheduler.done_target(target.name.clone());
+                    }
+                });
+            }
+        });
+
+        let most = counts.map(|(_, most)| most.into_inner());
+        assert_eq!(most, [2, 1]);
+        assert_eq!(state_of(&scheduler, "all"), TargetState::Succeeded);
     }
 }
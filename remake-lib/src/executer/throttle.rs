@@ -0,0 +1,71 @@
+//! Stop starting new targets when the system is busy, like `make -l`.
+//!
+//! The load average and the available memory are read from `/proc/loadavg` and `/proc/meminfo`.
+//! They are only there on Linux. On the other systems nothing is throttled.
+
+use std::time::Duration;
+
+/// How long the result of checking the system is used before checking it again.
+pub const THROTTLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The limits of the system. See `scheduler::TargetScheduler::get_next_target`.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    /// The max 1-minute load average.
+    pub max_load: Option<f64>,
+    /// The min available memory in bytes.
+    pub min_memory: Option<u64>,
+}
+
+impl Throttle {
+    pub fn is_set(&self) -> bool {
+        self.max_load.is_some() || self.min_memory.is_some()
+    }
+
+    /// Check why no new target should start now. None if one can.
+    pub fn check(&self) -> Option<String> {
+        // a value that can not be read never throttles
+        if let Some(max) = self.max_load {
+            match load_average() {
+                Some(load) if load > max => {
+                    return Some(format!("the load average {:.2} is over {:.2}", load, max))
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(min) = self.min_memory {
+            match available_memory() {
+                Some(memory) if memory < min => {
+                    return Some(format!(
+                        "the available memory {}MB is under {}MB",
+                        memory >> 20,
+                        min >> 20
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+/// The 1-minute load average. The first field of `/proc/loadavg`.
+pub fn load_average() -> Option<f64> {
+    let loadavg = std::fs::read_to_string("/proc/loadavg").ok()?;
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// The available memory in bytes. The `MemAvailable` of `/proc/meminfo`.
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+
+    let line = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemAvailable:"))?;
+
+    // like `MemAvailable:   12345678 kB`
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    kilobytes.checked_mul(1024)
+}
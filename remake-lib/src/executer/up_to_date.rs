@@ -0,0 +1,127 @@
+use crate::format::Target;
+use ahash::AHashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The reason why a target is not up to date.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Staleness {
+    /// The target is always executed because it has no outputs.
+    NoOutputs,
+    /// The target is executed because `Executer::always_execute` is set.
+    Forced,
+    MissingOutput(String),
+    MissingInput(String),
+    /// The input is newer than the oldest output.
+    NewerInput(String),
+    /// The build state has no record of the target.
+    NoRecord,
+    /// The commands or the outputs changed since the last execution.
+    CommandChanged,
+    /// The content of the input changed since the last execution.
+    InputChanged(String),
+}
+
+impl fmt::Display for Staleness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Staleness::NoOutputs => write!(f, "it has no outputs"),
+            Staleness::Forced => write!(f, "it is forced to execute"),
+            Staleness::MissingOutput(path) => write!(f, "the output `{}` does not exist", path),
+            Staleness::MissingInput(path) => write!(f, "the input `{}` does not exist", path),
+            Staleness::NewerInput(path) => {
+                write!(f, "the input `{}` is newer than the outputs", path)
+            }
+            Staleness::NoRecord => write!(f, "it has not been executed before"),
+            Staleness::CommandChanged => write!(f, "its commands changed"),
+            Staleness::InputChanged(path) => write!(f, "the input `{}` changed", path),
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Get the inputs of a target and the outputs of its dependences.
+/// The dependences without outputs are not considered.
+pub fn all_inputs<'a>(
+    target: &'a Target,
+    all_targets: &'a AHashMap<Arc<String>, Arc<Target>>,
+) -> impl Iterator<Item = &'a String> {
+    let dependence_outputs = target
+        .dependences
+        .iter()
+        .filter_map(|name| all_targets.get(name))
+        .flat_map(|dependence| dependence.outputs.iter());
+
+    target.inputs.iter().chain(dependence_outputs)
+}
+
+/// Check if a target need be executed by the modified time. See docs/standard_2.md.
+/// Return None if the target is up to date.
+///
+/// The target is up to date when it has outputs, all its inputs and outputs exist,
+/// and the oldest output is not older than its inputs and the outputs of its dependences.
+pub fn find_staleness(
+    target: &Target,
+    all_targets: &AHashMap<Arc<String>, Arc<Target>>,
+) -> Option<Staleness> {
+    if target.outputs.is_empty() {
+        return Some(Staleness::NoOutputs);
+    }
+
+    let mut oldest_output: Option<SystemTime> = None;
+
+    for output in target.outputs.iter() {
+        match modified(output) {
+            None => return Some(Staleness::MissingOutput(output.clone())),
+            Some(time) => {
+                oldest_output = Some(match oldest_output {
+                    Some(oldest) if oldest < time => oldest,
+                    _ => time,
+                });
+            }
+        }
+    }
+
+    let oldest_output = oldest_output.unwrap();
+
+    for input in all_inputs(target, all_targets) {
+        match modified(input) {
+            None => return Some(Staleness::MissingInput(input.clone())),
+            Some(time) if time > oldest_output => {
+                return Some(Staleness::NewerInput(input.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+
+    None
+}
+
+/// The modified times of the outputs of a target. None if the output does not exist.
+pub fn output_times(target: &Target) -> Vec<Option<SystemTime>> {
+    target
+        .outputs
+        .iter()
+        .map(|output| modified(output))
+        .collect()
+}
+
+/// The outputs that were created or modified since `output_times` returned `before`.
+pub fn changed_outputs<'a>(
+    target: &'a Target,
+    before: &'a [Option<SystemTime>],
+) -> impl Iterator<Item = &'a String> {
+    target
+        .outputs
+        .iter()
+        .zip(before.iter())
+        .filter(|(output, before)| {
+            let now = modified(output);
+            now.is_some() && now != **before
+        })
+        .map(|(output, _)| output)
+}
@@ -0,0 +1,324 @@
+use crate::errors::{BuilderError, GraphError};
+use crate::format::definition::CONSOLE_POOL;
+use crate::format::writer;
+use crate::format::{Command, CommandsRunable, Target};
+use crate::graph;
+use crate::parser::ParsedRemake;
+use ahash::{AHashMap, AHashSet};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+
+/// Build a command step by step.
+pub struct CommandBuilder {
+    executable: String,
+    arguments: Vec<String>,
+    ignore_error: bool,
+    environments: AHashMap<String, String>,
+    work_dir: String,
+    depfile: Option<String>,
+}
+
+impl CommandBuilder {
+    /// Create a command that runs the executable in the current directory.
+    pub fn new(executable: &str) -> CommandBuilder {
+        CommandBuilder {
+            executable: String::from(executable),
+            arguments: Vec::new(),
+            ignore_error: false,
+            environments: AHashMap::new(),
+            work_dir: String::from("."),
+            depfile: None,
+        }
+    }
+
+    pub fn argument(mut self, argument: &str) -> CommandBuilder {
+        self.arguments.push(String::from(argument));
+        self
+    }
+
+    pub fn arguments<I, S>(mut self, arguments: I) -> CommandBuilder
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.arguments
+            .extend(arguments.into_iter().map(|s| s.into()));
+        self
+    }
+
+    pub fn environment(mut self, key: &str, value: &str) -> CommandBuilder {
+        self.environments
+            .insert(String::from(key), String::from(value));
+        self
+    }
+
+    pub fn work_dir(mut self, work_dir: &str) -> CommandBuilder {
+        self.work_dir = String::from(work_dir);
+        self
+    }
+
+    pub fn ignore_error(mut self, ignore_error: bool) -> CommandBuilder {
+        self.ignore_error = ignore_error;
+        self
+    }
+
+    /// Set the depfile that the command writes. See docs/standard_3.md.
+    pub fn depfile(mut self, depfile: &str) -> CommandBuilder {
+        self.depfile = Some(String::from(depfile));
+        self
+    }
+
+    fn check(&self, target: &str) -> Result<(), BuilderError> {
+        if self.executable.is_empty() {
+            return Err(BuilderError::new(
+                target,
+                String::from("the executable of a command is empty"),
+            ));
+        }
+        if self.work_dir.is_empty() {
+            return Err(BuilderError::new(
+                target,
+                format!("the work directory of `{}` is empty", self.executable),
+            ));
+        }
+        if self.depfile.as_deref() == Some("") {
+            return Err(BuilderError::new(
+                target,
+                format!("the depfile of `{}` is empty", self.executable),
+            ));
+        }
+        Ok(())
+    }
+
+    fn build(self) -> CommandsRunable {
+        CommandsRunable {
+            command: Arc::new(Command {
+                executable: Arc::new(self.executable),
+                arguments: self.arguments,
+                ignore_error: AtomicBool::new(self.ignore_error),
+                environments: RwLock::new(self.environments),
+                work_dir: Arc::new(self.work_dir),
+                depfile: self.depfile,
+            }),
+        }
+    }
+}
+
+/// Build a target step by step.
+pub struct TargetBuilder {
+    name: String,
+    dependences: Vec<String>,
+    commands: Vec<CommandBuilder>,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    pool: Option<String>,
+}
+
+impl TargetBuilder {
+    pub fn new(name: &str) -> TargetBuilder {
+        TargetBuilder {
+            name: String::from(name),
+            dependences: Vec::new(),
+            commands: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            pool: None,
+        }
+    }
+
+    /// Add a dependence. The same dependence is only recorded once.
+    pub fn dependence(mut self, dependence: &str) -> TargetBuilder {
+        if !self.dependences.iter().any(|d| d == dependence) {
+            self.dependences.push(String::from(dependence));
+        }
+        self
+    }
+
+    /// Add a file that the commands read.
+    pub fn input(mut self, path: &str) -> TargetBuilder {
+        self.inputs.push(String::from(path));
+        self
+    }
+
+    /// Add a file that the commands write.
+    pub fn output(mut self, path: &str) -> TargetBuilder {
+        self.outputs.push(String::from(path));
+        self
+    }
+
+    /// Put the target into a pool. See docs/standard_4.md.
+    pub fn pool(mut self, pool: &str) -> TargetBuilder {
+        self.pool = Some(String::from(pool));
+        self
+    }
+
+    /// Append a command. Commands are executed in the order they are added.
+    pub fn command(mut self, command: CommandBuilder) -> TargetBuilder {
+        self.commands.push(command);
+        self
+    }
+
+    fn check(&self) -> Result<(), BuilderError> {
+        if self.name.is_empty() {
+            return Err(BuilderError::new(
+                &self.name,
+                String::from("the name of a target is empty"),
+            ));
+        }
+
+        for dependence in self.dependences.iter() {
+            if dependence.is_empty() {
+                return Err(BuilderError::new(
+                    &self.name,
+                    String::from("the name of a dependence is empty"),
+                ));
+            }
+            if *dependence == self.name {
+                return Err(BuilderError::new(
+                    &self.name,
+                    String::from("the target depends on itself"),
+                ));
+            }
+        }
+
+        for path in self.inputs.iter().chain(self.outputs.iter()) {
+            if path.is_empty() {
+                return Err(BuilderError::new(
+                    &self.name,
+                    String::from("the path of an input or output is empty"),
+                ));
+            }
+        }
+
+        if self.pool.as_deref() == Some("") {
+            return Err(BuilderError::new(
+                &self.name,
+                String::from("the name of the pool is empty"),
+            ));
+        }
+
+        for command in self.commands.iter() {
+            command.check(&self.name)?;
+        }
+        Ok(())
+    }
+
+    fn build(self) -> Target {
+        Target {
+            name: Arc::new(self.name),
+            dependences: Arc::new(self.dependences),
+            commands: Arc::new(self.commands.into_iter().map(|c| c.build()).collect()),
+            inputs: Arc::new(self.inputs),
+            outputs: Arc::new(self.outputs),
+            pool: self.pool,
+        }
+    }
+}
+
+/// Build a set of targets.
+///
+/// Every target is checked when it is added,
+/// and the dependences and the pools are checked by the `graph` module when the graph is built.
+pub struct GraphBuilder {
+    targets: Vec<TargetBuilder>,
+    names: AHashSet<String>,
+    pools: AHashMap<String, u64>,
+}
+
+impl GraphBuilder {
+    pub fn new() -> GraphBuilder {
+        GraphBuilder {
+            targets: Vec::new(),
+            names: AHashSet::new(),
+            pools: AHashMap::new(),
+        }
+    }
+
+    /// Define a pool that at most `depth` targets of it execute at the same time.
+    /// Fail if the depth is 0, or the pool is `console` or was defined before.
+    pub fn pool(&mut self, name: &str, depth: u64) -> Result<&mut GraphBuilder, BuilderError> {
+        let reason = if name.is_empty() {
+            Some(String::from("the name of the pool is empty"))
+        } else if name == CONSOLE_POOL {
+            Some(String::from("the `console` pool can not be defined"))
+        } else if depth == 0 {
+            Some(format!("the depth of the pool `{}` is 0", name))
+        } else if self.pools.contains_key(name) {
+            Some(format!("the pool `{}` is defined more than once", name))
+        } else {
+            None
+        };
+
+        if let Some(reason) = reason {
+            return Err(BuilderError {
+                target: None,
+                reason: Some(reason),
+            });
+        }
+
+        self.pools.insert(String::from(name), depth);
+        Ok(self)
+    }
+
+    /// Add a target. Fail if the target is invalid or its name was added before.
+    pub fn target(&mut self, target: TargetBuilder) -> Result<&mut GraphBuilder, BuilderError> {
+        target.check()?;
+
+        if self.names.contains(&target.name) {
+            return Err(BuilderError::new(
+                &target.name,
+                String::from("the target is defined more than once"),
+            ));
+        }
+
+        self.names.insert(target.name.clone());
+        self.targets.push(target);
+        Ok(self)
+    }
+
+    /// Check the dependences and build the targets.
+    /// The result can be passed to `executer::Executer::new`.
+    pub fn build(self) -> Result<ParsedRemake, BuilderError> {
+        let mut targets: AHashMap<Arc<String>, Arc<Target>> =
+            AHashMap::with_capacity(self.targets.len());
+
+        for target in self.targets {
+            let target = target.build();
+            targets.insert(target.name.clone(), Arc::new(target));
+        }
+
+        let mut errors = graph::check_targets(&targets);
+        errors.append(&mut graph::check_pools(&targets, &self.pools));
+
+        if let Some(error) = errors.into_iter().next() {
+            let target = match &error {
+                GraphError::UnknownDependence { target, .. }
+                | GraphError::SelfDependence { target }
+                | GraphError::DuplicateTarget { target }
+                | GraphError::UnknownTarget { target }
+                | GraphError::UnknownPool { target, .. } => target.clone(),
+                GraphError::Cycle { path } => path[0].clone(),
+                // `pool` never adds one
+                GraphError::EmptyPool { pool } | GraphError::ReservedPool { pool } => pool.clone(),
+            };
+            return Err(BuilderError::new(&target, error.to_string()));
+        }
+
+        Ok(ParsedRemake {
+            targets,
+            duplicates: Vec::new(),
+            pools: self.pools,
+        })
+    }
+
+    /// Check the dependences and serialize the targets into a build file.
+    pub fn build_bytes(self) -> Result<Vec<u8>, BuilderError> {
+        Ok(writer::write_to_bytes(&self.build()?))
+    }
+}
+
+impl Default for GraphBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
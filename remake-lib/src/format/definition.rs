@@ -1,3 +1,16 @@
+/// The version of the file format that this crate writes.
+/// All the versions up to it can be read.
+///
+/// - 0: docs/standard_1.md
+/// - 1: docs/standard_2.md. Targets have inputs and outputs.
+/// - 2: docs/standard_3.md. Commands have depfiles.
+/// - 3: docs/standard_4.md. Targets have pools.
+pub const VERSION: u64 = 3;
+
+/// The pool that every build file has. Its depth is 1,
+/// and the commands of its targets are not captured. See docs/standard_4.md.
+pub const CONSOLE_POOL: &str = "console";
+
 /// The platforam code.
 #[repr(u64)]
 pub enum Platform {
@@ -15,7 +28,25 @@ impl TryFrom<u64> for Platform {
             x if x == Platform::Window as u64 => Ok(Platform::Window),
             x if x == Platform::Unix as u64 => Ok(Platform::Unix),
             x if x == Platform::Mac as u64 => Ok(Platform::Mac),
+            x if x == Platform::Freebsd as u64 => Ok(Platform::Freebsd),
             _ => Err(()),
         }
     }
 }
+
+impl Platform {
+    /// The platform that remake was compiled for.
+    pub fn current() -> Platform {
+        cfg_if::cfg_if! {
+            if #[cfg(windows)] {
+                Platform::Window
+            } else if #[cfg(target_os = "macos")] {
+                Platform::Mac
+            } else if #[cfg(target_os = "freebsd")] {
+                Platform::Freebsd
+            } else {
+                Platform::Unix
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! The JSON and TOML formats.
+//!
+//! They describe the same targets as the binary format.
+//! Enable the `json` or `toml` feature to use them.
+
+use crate::errors::ParseError;
+use crate::format::builder::{CommandBuilder, GraphBuilder, TargetBuilder};
+use crate::format::Target;
+use crate::parser::ParsedRemake;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+/// A command in the interchange formats.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CommandDocument {
+    pub executable: String,
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    #[serde(default)]
+    pub ignore_error: bool,
+    #[serde(default)]
+    pub environments: BTreeMap<String, String>,
+    #[serde(default = "default_work_dir")]
+    pub work_dir: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub depfile: Option<String>,
+}
+
+fn default_work_dir() -> String {
+    String::from(".")
+}
+
+/// A target in the interchange formats.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TargetDocument {
+    pub name: String,
+    #[serde(default)]
+    pub dependences: Vec<String>,
+    #[serde(default)]
+    pub commands: Vec<CommandDocument>,
+    #[serde(default)]
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+}
+
+/// The root of the interchange formats.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GraphDocument {
+    #[serde(default)]
+    pub targets: Vec<TargetDocument>,
+    /// The depths of the pools. See docs/standard_4.md.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pools: BTreeMap<String, u64>,
+}
+
+impl GraphDocument {
+    /// Convert the targets into a document. Targets are sorted by name.
+    pub fn from_remake(remake: &ParsedRemake) -> GraphDocument {
+        let mut targets: Vec<&Target> = remake.targets.values().map(|t| t.as_ref()).collect();
+        targets.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        let targets = targets
+            .into_iter()
+            .map(|target| TargetDocument {
+                name: target.name.to_string(),
+                dependences: target.dependences.to_vec(),
+                commands: target
+                    .commands
+                    .iter()
+                    .map(|runable| {
+                        let command = &runable.command;
+                        CommandDocument {
+                            executable: command.executable.to_string(),
+                            arguments: command.arguments.clone(),
+                            ignore_error: command.ignore_error.load(Ordering::SeqCst),
+                            environments: command
+                                .environments
+                                .read()
+                                .unwrap()
+                                .iter()
+                                .map(|(k, v)| (k.clone(), v.clone()))
+                                .collect(),
+                            work_dir: command.work_dir.to_string(),
+                            depfile: command.depfile.clone(),
+                        }
+                    })
+                    .collect(),
+                inputs: target.inputs.to_vec(),
+                outputs: target.outputs.to_vec(),
+                pool: target.pool.clone(),
+            })
+            .collect();
+
+        GraphDocument {
+            targets,
+            pools: remake
+                .pools
+                .iter()
+                .map(|(name, depth)| (name.clone(), *depth))
+                .collect(),
+        }
+    }
+
+    /// Check the document and convert it into targets.
+    pub fn into_remake(self) -> Result<ParsedRemake, ParseError> {
+        let mut graph = GraphBuilder::new();
+
+        for (name, depth) in self.pools.iter() {
+            graph.pool(name, *depth).map_err(from_builder_error)?;
+        }
+
+        for target in self.targets {
+            let mut builder = TargetBuilder::new(&target.name);
+
+            for dependence in target.dependences.iter() {
+                builder = builder.dependence(dependence);
+            }
+
+            for input in target.inputs.iter() {
+                builder = builder.input(input);
+            }
+
+            for output in target.outputs.iter() {
+                builder = builder.output(output);
+            }
+
+            if let Some(pool) = &target.pool {
+                builder = builder.pool(pool);
+            }
+
+            for command in target.commands {
+                let mut command_builder = CommandBuilder::new(&command.executable)
+                    .arguments(command.arguments)
+                    .ignore_error(command.ignore_error)
+                    .work_dir(&command.work_dir);
+
+                if let Some(depfile) = &command.depfile {
+                    command_builder = command_builder.depfile(depfile);
+                }
+
+                for (k, v) in command.environments.iter() {
+                    command_builder = command_builder.environment(k, v);
+                }
+
+                builder = builder.command(command_builder);
+            }
+
+            graph.target(builder).map_err(from_builder_error)?;
+        }
+
+        graph.build().map_err(from_builder_error)
+    }
+}
+
+fn from_builder_error(err: crate::errors::BuilderError) -> ParseError {
+    let reason = err.reason.unwrap_or_default();
+
+    ParseError {
+        source: None,
+        source_span: None,
+        reason: Some(match err.target {
+            Some(target) => format!("the target `{}` is invalid:{}", target, reason),
+            // a pool
+            None => format!("the document is invalid:{}", reason),
+        }),
+    }
+}
+
+fn from_reason(format: &str, err: impl std::fmt::Display) -> ParseError {
+    ParseError {
+        source: None,
+        source_span: None,
+        reason: Some(format!("invalid {}:{}", format, err)),
+    }
+}
+
+/// parse a json document
+#[cfg(feature = "json")]
+pub fn parse_from_json(source: &str) -> Result<ParsedRemake, ParseError> {
+    let document: GraphDocument =
+        serde_json::from_str(source).map_err(|err| from_reason("json", err))?;
+    document.into_remake()
+}
+
+/// Serialize the targets into a json document.
+#[cfg(feature = "json")]
+pub fn write_to_json(remake: &ParsedRemake) -> String {
+    serde_json::to_string_pretty(&GraphDocument::from_remake(remake)).unwrap()
+}
+
+/// parse a toml document
+#[cfg(feature = "toml")]
+pub fn parse_from_toml(source: &str) -> Result<ParsedRemake, ParseError> {
+    let document: GraphDocument = toml::from_str(source).map_err(|err| from_reason("toml", err))?;
+    document.into_remake()
+}
+
+/// Serialize the targets into a toml document.
+#[cfg(feature = "toml")]
+pub fn write_to_toml(remake: &ParsedRemake) -> String {
+    toml::to_string_pretty(&GraphDocument::from_remake(remake)).unwrap()
+}
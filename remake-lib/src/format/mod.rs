@@ -1,7 +1,13 @@
+pub mod builder;
 pub mod definition;
+#[cfg(any(feature = "json", feature = "toml"))]
+pub mod interchange;
+pub mod writer;
 
 use crate::errors::RuntimeError;
 use ahash::AHashMap;
+use std::io::{self, Read};
+use std::time::{Duration, Instant};
 use std::{ffi::OsStr, sync::atomic::AtomicBool, sync::Arc, sync::RwLock};
 
 /// This stands for a target.
@@ -11,6 +17,13 @@ pub struct Target {
     pub name: Arc<String>,
     pub dependences: Arc<Vec<String>>,
     pub commands: Arc<Vec<CommandsRunable>>,
+    /// The files that the commands read.
+    pub inputs: Arc<Vec<String>>,
+    /// The files that the commands write.
+    /// A target without outputs is always executed.
+    pub outputs: Arc<Vec<String>>,
+    /// The pool that limits how many targets execute at the same time. See docs/standard_4.md.
+    pub pool: Option<String>,
 }
 
 /// This stands for a command that will be executed.
@@ -25,6 +38,33 @@ pub struct Command {
     pub environments: RwLock<AHashMap<String, String>>,
     /// The work directory of the command
     pub work_dir: Arc<String>,
+    /// The Makefile-style depfile that the command writes, like `gcc -MD`.
+    /// Relative to the work directory.
+    pub depfile: Option<String>,
+}
+
+/// How to execute a command. See `Command::run_with`.
+#[derive(Debug, Clone, Default)]
+pub struct RunOptions {
+    /// Capture the stdout and stderr. See `Command::run_captured`.
+    pub capture: bool,
+    /// More environment variables. They override the ones of the command.
+    pub environments: Vec<(String, String)>,
+    /// Start the program in its own process group, with no stdin. Only on unix.
+    /// So Ctrl-C does not reach it, and it never stops to read the terminal.
+    /// See `executer::interrupt`.
+    pub process_group: bool,
+}
+
+/// How a command was executed. See `Command::run_with`.
+pub struct CommandRun {
+    /// The process id. None if the program was not started.
+    pub pid: Option<u32>,
+    /// The exit code. None if the program was not started or was terminated by a signal.
+    pub exit_code: Option<i32>,
+    pub duration: Duration,
+    /// The same as `Command::run_captured`.
+    pub result: Result<Vec<u8>, RuntimeError>,
 }
 
 /// The commands Runable contains a set of commands.
@@ -35,6 +75,72 @@ pub struct CommandsRunable {
 impl Command {
     /// Execute a command
     pub fn run(&self) -> Result<(), RuntimeError> {
+        self.run_with(&RunOptions::default(), &mut |_| {}, &mut |_| {})
+            .result
+            .map(|_| ())
+    }
+
+    /// Execute a command and capture its stdout and stderr.
+    ///
+    /// They are written into one pipe, so the output keeps the order that the program wrote it.
+    /// If the command fails, the output is in the `RuntimeError` too.
+    pub fn run_captured(&self) -> Result<Vec<u8>, RuntimeError> {
+        let options = RunOptions {
+            capture: true,
+            ..RunOptions::default()
+        };
+        self.run_with(&options, &mut |_| {}, &mut |_| {}).result
+    }
+
+    /// Execute a command by the options,
+    /// and call `spawned` with the process id once the program is started.
+    ///
+    /// `exited` is called when the program exited. On unix it is not reaped yet,
+    /// so its process id is not reused by another process before `exited` returns.
+    pub fn run_with(
+        &self,
+        options: &RunOptions,
+        spawned: &mut dyn FnMut(u32),
+        exited: &mut dyn FnMut(u32),
+    ) -> CommandRun {
+        let begin = Instant::now();
+        let mut pid = None;
+        let mut exit_code = None;
+
+        let result = self.execute(
+            options,
+            &mut |id| {
+                pid = Some(id);
+                spawned(id);
+            },
+            exited,
+            &mut exit_code,
+        );
+
+        CommandRun {
+            pid,
+            exit_code,
+            duration: begin.elapsed(),
+            result,
+        }
+    }
+
+    fn error(&self, source: Option<io::Error>, reason: String, output: &[u8]) -> RuntimeError {
+        RuntimeError {
+            source: source.map(|err| Arc::new(err) as Arc<dyn std::error::Error>),
+            command: Some(format!("{} {:#?}", self.executable, self.arguments)),
+            reason: Some(reason),
+            output: (!output.is_empty()).then(|| String::from_utf8_lossy(output).into_owned()),
+        }
+    }
+
+    fn execute(
+        &self,
+        options: &RunOptions,
+        spawned: &mut dyn FnMut(u32),
+        exited: &mut dyn FnMut(u32),
+        exit_code: &mut Option<i32>,
+    ) -> Result<Vec<u8>, RuntimeError> {
         // set up command
         let mut command = std::process::Command::new(self.executable.as_str());
 
@@ -48,51 +154,127 @@ impl Command {
             command.env(OsStr::new(env.0.as_str()), OsStr::new(env.1.as_str()));
         }
 
+        for (name, value) in options.environments.iter() {
+            command.env(name, value);
+        }
+
+        #[cfg(unix)]
+        if options.process_group {
+            use std::os::unix::process::CommandExt;
+
+            command.process_group(0);
+            command.stdin(std::process::Stdio::null());
+        }
+
+        let mut reader = None;
+
+        if options.capture {
+            let pipe =
+                io::pipe().and_then(|(reader, writer)| Ok((reader, writer.try_clone()?, writer)));
+
+            match pipe {
+                Ok((pipe_reader, stdout, stderr)) => {
+                    command.stdout(stdout);
+                    command.stderr(stderr);
+                    reader = Some(pipe_reader);
+                }
+                Err(err) => {
+                    return Err(self.error(
+                        Some(err),
+                        String::from("can not create a pipe for the output"),
+                        &[],
+                    ))
+                }
+            }
+        }
+
         // run
         let child = command.spawn();
 
+        // close the writing ends that the `command` holds, or the reading never ends
+        drop(command);
+
+        let mut output = Vec::new();
+
         match child {
             Ok(mut ret) => {
+                spawned(ret.id());
+
+                if let Some(mut reader) = reader {
+                    // read until the program and its children close the pipe
+                    if let Err(err) = reader.read_to_end(&mut output) {
+                        let _ = ret.kill();
+                        wait_without_reaping(&ret);
+                        exited(ret.id());
+                        let _ = ret.wait();
+                        return Err(self.error(
+                            Some(err),
+                            String::from("can not read the output of the program"),
+                            &output,
+                        ));
+                    }
+                }
+
+                wait_without_reaping(&ret);
+                exited(ret.id());
                 let exit_status = ret.wait();
 
-                if self.ignore_error.load(std::sync::atomic::Ordering::SeqCst) {
-                    return Ok(());
-                } else {
-                    match exit_status {
-                        Ok(status) => {
-                            if status.success() {
-                                return Ok(());
-                            } else {
-                                return Err(RuntimeError {
-                                    source: None,
-                                    command: Some(format!(
-                                        "{} {:#?}",
-                                        self.executable, self.arguments
-                                    )),
-                                    reason: Some(format!(
-                                        "the program executed but return {}",
-                                        status.code().unwrap()
-                                    )),
-                                });
+                if let Ok(status) = &exit_status {
+                    *exit_code = status.code();
+                }
+
+                match exit_status {
+                    _ if self.ignore_error.load(std::sync::atomic::Ordering::SeqCst) => Ok(output),
+                    Ok(status) if status.success() => Ok(output),
+                    Ok(status) => Err(self.error(
+                        None,
+                        match status.code() {
+                            Some(code) => format!("the program executed but return {}", code),
+                            None => {
+                                String::from("the program executed but was terminated by a signal")
                             }
-                        }
-                        Err(err) => {
-                            return Err(RuntimeError {
-                                source: Some(Arc::new(err)),
-                                command: Some(format!("{} {:#?}", self.executable, self.arguments)),
-                                reason: Some(String::from("can not execute the program")),
-                            });
-                        }
-                    }
+                        },
+                        &output,
+                    )),
+                    Err(err) => Err(self.error(
+                        Some(err),
+                        String::from("can not execute the program"),
+                        &output,
+                    )),
                 }
             }
-            Err(err) => {
-                return Err(RuntimeError {
-                    source: Some(Arc::new(err)),
-                    command: Some(format!("{} {:#?}", self.executable, self.arguments)),
-                    reason: Some(String::from("can not start the program")),
-                })
+            Err(err) => Err(self.error(
+                Some(err),
+                String::from("can not start the program"),
+                &output,
+            )),
+        }
+    }
+}
+
+/// Block until the program exits, but keep it as a zombie, so its process id is still taken.
+/// Only on unix. On the other systems this returns at once.
+fn wait_without_reaping(child: &std::process::Child) {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            loop {
+                let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+                let waited = unsafe {
+                    libc::waitid(
+                        libc::P_PID,
+                        child.id() as libc::id_t,
+                        &mut info,
+                        libc::WEXITED | libc::WNOWAIT,
+                    )
+                };
+
+                // the `wait` after this reports the other errors
+                if waited == 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    break;
+                }
             }
+        } else {
+            let _ = child;
         }
     }
 }
@@ -100,12 +282,11 @@ impl Command {
 impl CommandsRunable {
     /// Execute all the commands
     pub fn run(&self) -> Result<(), RuntimeError> {
-        let result = self.command.run();
-
-        if result.is_err() {
-            return result;
-        }
+        self.command.run()
+    }
 
-        return Ok(());
+    /// Execute all the commands and capture their output. See `Command::run_captured`.
+    pub fn run_captured(&self) -> Result<Vec<u8>, RuntimeError> {
+        self.command.run_captured()
     }
 }
@@ -0,0 +1,264 @@
+use crate::format::definition::{Platform, VERSION};
+use crate::format::{Command, Target};
+use crate::parser::ParsedRemake;
+use ahash::AHashMap;
+use std::sync::atomic::Ordering;
+
+/// Write bytes as the layout of the current version. See `definition::VERSION`.
+pub(crate) struct ByteWriter {
+    buffer: Vec<u8>,
+}
+
+impl ByteWriter {
+    pub fn new() -> ByteWriter {
+        ByteWriter {
+            buffer: Vec::with_capacity(1024),
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub fn write_boolean(&mut self, value: bool) {
+        self.write(&[u8::from(value)]);
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_u64(value.len() as u64);
+        self.write(value.as_bytes());
+    }
+
+    pub fn write_string_array(&mut self, values: &[String]) {
+        self.write_u64(values.len() as u64);
+
+        for value in values {
+            self.write_string(value);
+        }
+    }
+
+    /// The pairs are sorted by key so that the output is reproducible.
+    pub fn write_string_map(&mut self, map: &AHashMap<String, String>) {
+        let mut pairs: Vec<(&String, &String)> = map.iter().collect();
+        pairs.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+        self.write_u64(pairs.len() as u64);
+
+        for (k, v) in pairs {
+            self.write_string(k);
+            self.write_string(v);
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+/// write a command
+pub(crate) fn write_command(writer: &mut ByteWriter, command: &Command) {
+    writer.write_string(&command.executable);
+    writer.write_string_array(&command.arguments);
+    writer.write_boolean(command.ignore_error.load(Ordering::SeqCst));
+    writer.write_string_map(&command.environments.read().unwrap());
+    writer.write_string(&command.work_dir);
+    // empty if there is no depfile
+    writer.write_string(command.depfile.as_deref().unwrap_or(""));
+}
+
+/// write a target
+fn write_target(writer: &mut ByteWriter, target: &Target) {
+    writer.write_string(&target.name);
+    writer.write_string_array(&target.dependences);
+
+    writer.write_u64(target.commands.len() as u64);
+
+    for command in target.commands.iter() {
+        write_command(writer, &command.command);
+    }
+
+    writer.write_string_array(&target.inputs);
+    writer.write_string_array(&target.outputs);
+    // empty if there is no pool
+    writer.write_string(target.pool.as_deref().unwrap_or(""));
+}
+
+/// Serialize targets into the bytes of a build file.
+///
+/// The header is written for the current platform and version,
+/// so the output can be read back by `parser::parse_from_bytes`.
+/// Targets are written in the order that they are given.
+/// No pool is written, use `write_to_bytes` for them.
+pub fn write_targets<'a, I>(targets: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a Target>,
+{
+    write_targets_and_pools(targets, &AHashMap::new())
+}
+
+fn write_targets_and_pools<'a, I>(targets: I, pools: &AHashMap<String, u64>) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a Target>,
+{
+    let targets: Vec<&Target> = targets.into_iter().collect();
+    let mut writer = ByteWriter::new();
+
+    // header
+    writer.write(b"remake");
+    writer.write_u64(Platform::current() as u64);
+    writer.write_u64(VERSION);
+
+    // targets
+    writer.write_u64(targets.len() as u64);
+
+    for target in targets {
+        write_target(&mut writer, target);
+    }
+
+    // pools, sorted by name
+    let mut pools: Vec<(&String, &u64)> = pools.iter().collect();
+    pools.sort_unstable();
+
+    writer.write_u64(pools.len() as u64);
+
+    for (name, depth) in pools {
+        writer.write_string(name);
+        writer.write_u64(*depth);
+    }
+
+    writer.into_bytes()
+}
+
+/// Serialize a parsed build file back into bytes.
+///
+/// `parser::parse_from_bytes(write_to_bytes(x))` yields the same targets and pools as `x`.
+/// Targets are sorted by name so that the output is reproducible.
+pub fn write_to_bytes(remake: &ParsedRemake) -> Vec<u8> {
+    let mut targets: Vec<&Target> = remake.targets.values().map(|t| t.as_ref()).collect();
+    targets.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    write_targets_and_pools(targets, &remake.pools)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::builder::{CommandBuilder, GraphBuilder, TargetBuilder};
+    use crate::parser::{parse_from_bytes, ByteReader};
+
+    fn sample() -> ParsedRemake {
+        let mut graph = GraphBuilder::new();
+        graph.pool("link", 2).unwrap();
+        graph
+            .target(
+                TargetBuilder::new("app")
+                    .dependence("lib")
+                    .dependence("gen")
+                    .input("main.c")
+                    .output("app")
+                    .pool("link")
+                    .command(
+                        CommandBuilder::new("gcc")
+                            .arguments(["-o", "app", "main.c", "libfoo.a"])
+                            .environment("CC", "gcc")
+                            .work_dir("build"),
+                    ),
+            )
+            .unwrap()
+            .target(
+                TargetBuilder::new("lib")
+                    .input("foo.c")
+                    .output("build/libfoo.a")
+                    .command(
+                        CommandBuilder::new("gcc")
+                            .arguments(["-MD", "-MF", "foo.d", "-c", "foo.c"])
+                            .depfile("foo.d"),
+                    )
+                    .command(CommandBuilder::new("rm").argument("tmp").ignore_error(true)),
+            )
+            .unwrap()
+            .target(TargetBuilder::new("gen"))
+            .unwrap();
+        graph.build().unwrap()
+    }
+
+    fn assert_same_command(left: &Command, right: &Command) {
+        assert_eq!(left.executable, right.executable);
+        assert_eq!(left.arguments, right.arguments);
+        assert_eq!(
+            left.ignore_error.load(Ordering::SeqCst),
+            right.ignore_error.load(Ordering::SeqCst)
+        );
+        assert_eq!(
+            *left.environments.read().unwrap(),
+            *right.environments.read().unwrap()
+        );
+        assert_eq!(left.work_dir, right.work_dir);
+        assert_eq!(left.depfile, right.depfile);
+    }
+
+    fn assert_same(left: &ParsedRemake, right: &ParsedRemake) {
+        assert_eq!(left.pools, right.pools);
+        assert_eq!(left.targets.len(), right.targets.len());
+
+        for (name, left) in left.targets.iter() {
+            let right = &right.targets[name];
+            assert_eq!(left.name, right.name);
+            assert_eq!(left.dependences, right.dependences);
+            assert_eq!(left.inputs, right.inputs);
+            assert_eq!(left.outputs, right.outputs);
+            assert_eq!(left.pool, right.pool);
+            assert_eq!(left.commands.len(), right.commands.len());
+
+            for (l, r) in left.commands.iter().zip(right.commands.iter()) {
+                assert_same_command(&l.command, &r.command);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let remake = sample();
+        let bytes = write_to_bytes(&remake);
+        let parsed = parse_from_bytes(bytes.clone()).unwrap();
+
+        assert_same(&remake, &parsed);
+        assert!(parsed.duplicates.is_empty());
+        // sorted by name, so the same graph gives the same bytes
+        assert_eq!(write_to_bytes(&parsed), bytes);
+    }
+
+    #[test]
+    fn round_trip_without_targets() {
+        let remake = GraphBuilder::new().build().unwrap();
+        let parsed = parse_from_bytes(write_to_bytes(&remake)).unwrap();
+
+        assert_same(&remake, &parsed);
+    }
+
+    #[test]
+    fn truncated_bytes_are_an_error() {
+        let bytes = write_to_bytes(&sample());
+
+        for length in 0..bytes.len() {
+            assert!(parse_from_bytes(bytes[..length].to_vec()).is_err());
+        }
+    }
+
+    #[test]
+    fn read_up_to_the_end() {
+        // the last field of a file ends at the last byte
+        let bytes = [1u8, 2, 3, 4];
+        let mut reader = ByteReader::new(&bytes);
+
+        assert_eq!(reader.read(1).unwrap(), &[1]);
+        assert_eq!(reader.read(3).unwrap(), &[2, 3, 4]);
+        assert_eq!(reader.remaining(), 0);
+        assert_eq!(reader.read(0).unwrap(), &[] as &[u8]);
+        assert!(reader.read(1).is_err());
+    }
+}
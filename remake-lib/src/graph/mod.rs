@@ -0,0 +1,432 @@
+use crate::errors::{GraphError, ValidationError};
+use crate::format::definition::CONSOLE_POOL;
+use crate::format::Target;
+use crate::parser::ParsedRemake;
+use ahash::AHashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Check the targets before execute them.
+///
+/// Report duplicate targets, self dependences, dependences that are not defined,
+/// dependence cycles and the problems of the pools.
+pub fn validate(remake: &ParsedRemake) -> Result<(), ValidationError> {
+    let mut errors: Vec<GraphError> = remake
+        .duplicates
+        .iter()
+        .map(|name| GraphError::DuplicateTarget {
+            target: name.to_string(),
+        })
+        .collect();
+
+    errors.append(&mut check_targets(&remake.targets));
+    errors.append(&mut check_pools(&remake.targets, &remake.pools));
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationError { errors })
+    }
+}
+
+/// Report the requested targets that are not defined.
+pub fn check_requested(
+    targets: &AHashMap<Arc<String>, Arc<Target>>,
+    requested: &[String],
+) -> Vec<GraphError> {
+    requested
+        .iter()
+        .filter(|name| !targets.contains_key(*name))
+        .map(|name| GraphError::UnknownTarget {
+            target: name.clone(),
+        })
+        .collect()
+}
+
+/// Report self dependences, dependences that are not defined and dependence cycles.
+/// The targets are checked in the order of their names so the result is stable.
+pub fn check_targets(targets: &AHashMap<Arc<String>, Arc<Target>>) -> Vec<GraphError> {
+    let mut errors = Vec::new();
+
+    let mut names: Vec<&Arc<String>> = targets.keys().collect();
+    names.sort_unstable();
+
+    let indexes: AHashMap<&str, usize> = names
+        .iter()
+        .enumerate()
+        .map(|(index, name)| (name.as_str(), index))
+        .collect();
+
+    // build the edges and check the dependences
+    let mut edges: Vec<Vec<usize>> = Vec::with_capacity(names.len());
+
+    for name in names.iter() {
+        let target = &targets[*name];
+        let mut edge = Vec::with_capacity(target.dependences.len());
+
+        for dependence in target.dependences.iter() {
+            if dependence == name.as_str() {
+                errors.push(GraphError::SelfDependence {
+                    target: name.to_string(),
+                });
+                continue;
+            }
+
+            match indexes.get(dependence.as_str()) {
+                Some(index) => edge.push(*index),
+                None => errors.push(GraphError::UnknownDependence {
+                    target: name.to_string(),
+                    dependence: dependence.clone(),
+                }),
+            }
+        }
+        edges.push(edge);
+    }
+
+    for cycle in find_cycles(&edges) {
+        errors.push(GraphError::Cycle {
+            path: cycle
+                .iter()
+                .map(|index| names[*index].to_string())
+                .collect(),
+        });
+    }
+
+    errors
+}
+
+/// Report the pools with depth 0 and the targets in the pools that are not defined.
+/// The `console` pool is always defined, so it can not be defined again.
+/// The errors are in the order of the names so the result is stable.
+pub fn check_pools(
+    targets: &AHashMap<Arc<String>, Arc<Target>>,
+    pools: &AHashMap<String, u64>,
+) -> Vec<GraphError> {
+    let mut errors: Vec<GraphError> = Vec::new();
+
+    if pools.contains_key(CONSOLE_POOL) {
+        errors.push(GraphError::ReservedPool {
+            pool: String::from(CONSOLE_POOL),
+        });
+    }
+
+    let mut empty: Vec<&String> = pools
+        .iter()
+        .filter(|(name, depth)| **depth == 0 && *name != CONSOLE_POOL)
+        .map(|(name, _)| name)
+        .collect();
+    empty.sort_unstable();
+
+    errors.extend(
+        empty
+            .into_iter()
+            .map(|pool| GraphError::EmptyPool { pool: pool.clone() }),
+    );
+
+    let mut names: Vec<&Arc<String>> = targets.keys().collect();
+    names.sort_unstable();
+
+    for name in names {
+        if let Some(pool) = &targets[name].pool {
+            if pool != CONSOLE_POOL && !pools.contains_key(pool) {
+                errors.push(GraphError::UnknownPool {
+                    target: name.to_string(),
+                    pool: pool.clone(),
+                });
+            }
+        }
+    }
+
+    errors
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Unvisited,
+    Visiting,
+    Visited,
+}
+
+/// Find the cycles by a depth-first search.
+/// Every back edge reports a cycle. The first node is repeated at the end of a cycle.
+/// The search does not recurse so deep graphs are fine.
+fn find_cycles(edges: &[Vec<usize>]) -> Vec<Vec<usize>> {
+    let mut cycles = Vec::new();
+    let mut marks = vec![Mark::Unvisited; edges.len()];
+
+    // (node, index of the next edge)
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+
+    for root in 0..edges.len() {
+        if marks[root] != Mark::Unvisited {
+            continue;
+        }
+
+        marks[root] = Mark::Visiting;
+        stack.push((root, 0));
+
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+
+            if *next == edges[node].len() {
+                marks[node] = Mark::Visited;
+                stack.pop();
+                continue;
+            }
+
+            let to = edges[node][*next];
+            *next += 1;
+
+            match marks[to] {
+                Mark::Unvisited => {
+                    marks[to] = Mark::Visiting;
+                    stack.push((to, 0));
+                }
+                Mark::Visiting => {
+                    let begin = stack.iter().position(|(n, _)| *n == to).unwrap();
+                    let mut cycle: Vec<usize> = stack[begin..].iter().map(|(n, _)| *n).collect();
+                    cycle.push(to);
+                    cycles.push(cycle);
+                }
+                Mark::Visited => {}
+            }
+        }
+    }
+
+    cycles
+}
+
+/// The chain of dependences that took the longest time.
+pub struct CriticalPath {
+    /// From the first target executed to the last.
+    pub targets: Vec<Arc<String>>,
+    pub duration: Duration,
+}
+
+/// Find the critical path of a build by the durations of the targets.
+/// The build can not be faster than it with any number of threads.
+///
+/// Only the targets with a duration are on the path, so the skipped ones are not.
+/// The targets must have no cycles, see `check_targets`.
+pub fn critical_path(
+    targets: &AHashMap<Arc<String>, Arc<Target>>,
+    durations: &AHashMap<Arc<String>, Duration>,
+) -> CriticalPath {
+    let mut names: Vec<&Arc<String>> = durations.keys().collect();
+    names.sort_unstable();
+
+    let indexes: AHashMap<&str, usize> = names
+        .iter()
+        .enumerate()
+        .map(|(index, name)| (name.as_str(), index))
+        .collect();
+
+    let edges: Vec<Vec<usize>> = names
+        .iter()
+        .map(|name| match targets.get(*name) {
+            Some(target) => target
+                .dependences
+                .iter()
+                .filter_map(|dependence| indexes.get(dependence.as_str()).copied())
+                .collect(),
+            None => Vec::new(),
+        })
+        .collect();
+
+    // the duration of the longest chain that ends with a node,
+    // and the dependence before the node on the chain
+    let mut longest: Vec<Duration> = vec![Duration::ZERO; names.len()];
+    let mut before: Vec<Option<usize>> = vec![None; names.len()];
+    let mut marks = vec![Mark::Unvisited; names.len()];
+
+    // the same search as `find_cycles`, a node is done after its dependences
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+
+    for root in 0..names.len() {
+        if marks[root] != Mark::Unvisited {
+            continue;
+        }
+
+        marks[root] = Mark::Visiting;
+        stack.push((root, 0));
+
+        while let Some((node, next)) = stack.last_mut() {
+            let node = *node;
+
+            if *next == edges[node].len() {
+                let chain = edges[node]
+                    .iter()
+                    .filter(|to| marks[**to] == Mark::Visited)
+                    .max_by_key(|to| longest[**to]);
+
+                longest[node] =
+                    durations[names[node]] + chain.map_or(Duration::ZERO, |to| longest[*to]);
+                before[node] = chain.copied();
+
+                marks[node] = Mark::Visited;
+                stack.pop();
+                continue;
+            }
+
+            let to = edges[node][*next];
+            *next += 1;
+
+            if marks[to] == Mark::Unvisited {
+                marks[to] = Mark::Visiting;
+                stack.push((to, 0));
+            }
+        }
+    }
+
+    let mut path = CriticalPath {
+        targets: Vec::new(),
+        duration: Duration::ZERO,
+    };
+
+    let mut node = match (0..names.len()).max_by_key(|node| longest[*node]) {
+        Some(node) => node,
+        None => return path,
+    };
+    path.duration = longest[node];
+
+    loop {
+        path.targets.push(names[node].clone());
+
+        match before[node] {
+            Some(dependence) => node = dependence,
+            None => break,
+        }
+    }
+
+    path.targets.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::builder::{GraphBuilder, TargetBuilder};
+    use crate::format::writer::write_targets;
+    use crate::parser::parse_from_bytes;
+
+    /// The builder refuses the bad graphs, so the targets are made directly.
+    fn target(name: &str, dependences: &[&str]) -> Target {
+        Target {
+            name: Arc::new(String::from(name)),
+            dependences: Arc::new(dependences.iter().map(|d| String::from(*d)).collect()),
+            commands: Arc::new(Vec::new()),
+            inputs: Arc::new(Vec::new()),
+            outputs: Arc::new(Vec::new()),
+            pool: None,
+        }
+    }
+
+    fn graph(targets: &[(&str, &[&str])]) -> AHashMap<Arc<String>, Arc<Target>> {
+        targets
+            .iter()
+            .map(|(name, dependences)| {
+                let target = target(name, dependences);
+                (target.name.clone(), Arc::new(target))
+            })
+            .collect()
+    }
+
+    fn strings(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    #[test]
+    fn report_the_path_of_a_cycle() {
+        let targets = graph(&[
+            ("a", &["b"]),
+            ("b", &["c"]),
+            ("c", &["a"]),
+            // depends on the cycle but is not in it
+            ("d", &["a"]),
+            ("x", &["y"]),
+            ("y", &["x"]),
+        ]);
+
+        assert_eq!(
+            check_targets(&targets),
+            vec![
+                GraphError::Cycle {
+                    path: strings(&["a", "b", "c", "a"])
+                },
+                GraphError::Cycle {
+                    path: strings(&["x", "y", "x"])
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn report_the_bad_dependences() {
+        let targets = graph(&[
+            ("self", &["self"]),
+            ("unknown", &["missing", "self"]),
+            ("fine", &["unknown"]),
+        ]);
+
+        // a self dependence is not a cycle too
+        assert_eq!(
+            check_targets(&targets),
+            vec![
+                GraphError::SelfDependence {
+                    target: String::from("self")
+                },
+                GraphError::UnknownDependence {
+                    target: String::from("unknown"),
+                    dependence: String::from("missing")
+                },
+            ]
+        );
+        assert_eq!(
+            check_requested(&targets, &strings(&["fine", "nothing"])),
+            vec![GraphError::UnknownTarget {
+                target: String::from("nothing")
+            }]
+        );
+    }
+
+    #[test]
+    fn report_the_duplicate_targets() {
+        let (first, second, other) = (target("a", &[]), target("a", &["b"]), target("b", &[]));
+        let remake = parse_from_bytes(write_targets([&first, &second, &other])).unwrap();
+
+        // the first one is kept
+        assert!(remake.targets[&String::from("a")].dependences.is_empty());
+        assert_eq!(
+            validate(&remake).unwrap_err().errors,
+            vec![GraphError::DuplicateTarget {
+                target: String::from("a")
+            }]
+        );
+    }
+
+    #[test]
+    fn a_good_graph_passes() {
+        let targets = graph(&[("app", &["lib", "gen"]), ("lib", &["gen"]), ("gen", &[])]);
+
+        assert!(check_targets(&targets).is_empty());
+    }
+
+    #[test]
+    fn the_console_pool_can_not_be_defined() {
+        let mut graph = GraphBuilder::new();
+        graph
+            .target(TargetBuilder::new("shell").pool(CONSOLE_POOL))
+            .unwrap();
+        let mut remake = graph.build().unwrap();
+
+        // only a file can define it, the builder refuses
+        remake.pools.insert(String::from(CONSOLE_POOL), 4);
+
+        assert_eq!(
+            validate(&remake).unwrap_err().errors,
+            vec![GraphError::ReservedPool {
+                pool: String::from(CONSOLE_POOL)
+            }]
+        );
+    }
+}
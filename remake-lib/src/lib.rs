@@ -1,6 +1,9 @@
 #![feature(thread_id_value)]
 
+pub mod cache;
 pub mod errors;
 pub mod executer;
 pub mod format;
+pub mod graph;
 pub mod parser;
+pub mod state;
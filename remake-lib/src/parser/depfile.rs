@@ -0,0 +1,219 @@
+//! The Makefile-style depfiles that compilers write, like `gcc -MD` or `clang -MF`.
+//!
+//! ```text
+//! main.o: main.c config.h \
+//!   include/util.h
+//! config.h:
+//! ```
+//!
+//! Only the rules are supported, no variables or recipes.
+//! `\ ` is a space and `\#` is a `#` in a path. `$$` is a `$`.
+//! A `\` at the end of a line joins the next line.
+
+use crate::errors::SyntaxError;
+use ahash::AHashSet;
+
+struct DepfileParser {
+    /// The prerequisites of all the rules, without repeats.
+    prerequisites: Vec<String>,
+    seen: AHashSet<String>,
+    /// The word being read and where it begins.
+    word: String,
+    word_line: usize,
+    word_column: usize,
+    /// Whether the `:` of the current rule was read.
+    after_colon: bool,
+    /// Where the first target of the current rule begins.
+    rule_begin: Option<(usize, usize)>,
+}
+
+impl DepfileParser {
+    fn end_word(&mut self) {
+        if self.word.is_empty() {
+            return;
+        }
+
+        let word = std::mem::take(&mut self.word);
+
+        if self.after_colon {
+            if self.seen.insert(word.clone()) {
+                self.prerequisites.push(word);
+            }
+        } else if self.rule_begin.is_none() {
+            self.rule_begin = Some((self.word_line, self.word_column));
+        }
+    }
+
+    fn end_rule(&mut self) -> Result<(), SyntaxError> {
+        self.end_word();
+
+        if let (Some((line, column)), false) = (self.rule_begin, self.after_colon) {
+            return Err(SyntaxError {
+                line,
+                column,
+                reason: String::from("expect `:` after the targets of a rule"),
+            });
+        }
+
+        self.after_colon = false;
+        self.rule_begin = None;
+        Ok(())
+    }
+}
+
+/// Check if the rest chars begin with a whitespace, a joined line or nothing.
+fn ends_word(mut rest: std::iter::Peekable<std::str::Chars<'_>>) -> bool {
+    match rest.next() {
+        None => true,
+        Some('\\') => matches!(rest.next(), Some('\n') | Some('\r')),
+        Some(c) => c.is_whitespace(),
+    }
+}
+
+/// Parse a depfile. Return the prerequisites of all the rules in order.
+/// The targets of the rules are ignored.
+pub fn parse_depfile(source: &str) -> Result<Vec<String>, SyntaxError> {
+    let mut parser = DepfileParser {
+        prerequisites: Vec::new(),
+        seen: AHashSet::new(),
+        word: String::new(),
+        word_line: 1,
+        word_column: 1,
+        after_colon: false,
+        rule_begin: None,
+    };
+
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    let mut column = 0;
+
+    while let Some(c) = chars.next() {
+        column += 1;
+
+        if parser.word.is_empty() {
+            parser.word_line = line;
+            parser.word_column = column;
+        }
+
+        match c {
+            '\n' => {
+                parser.end_rule()?;
+                line += 1;
+                column = 0;
+            }
+            ' ' | '\t' | '\r' => parser.end_word(),
+            '\\' => match chars.peek() {
+                Some('\n') => {
+                    // join the next line
+                    chars.next();
+                    parser.end_word();
+                    line += 1;
+                    column = 0;
+                }
+                Some('\r') => {
+                    chars.next();
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    parser.end_word();
+                    line += 1;
+                    column = 0;
+                }
+                Some(' ') | Some('#') => {
+                    parser.word.push(chars.next().unwrap());
+                    column += 1;
+                }
+                // a Windows path
+                _ => parser.word.push('\\'),
+            },
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                column += 1;
+                parser.word.push('$');
+            }
+            '#' if parser.word.is_empty() => {
+                // a comment
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            }
+            // `C:\a.h` is a path but `a.o: a.h` is a rule
+            ':' if !parser.after_colon && ends_word(chars.clone()) => {
+                parser.end_word();
+
+                if parser.rule_begin.is_none() {
+                    return Err(SyntaxError {
+                        line,
+                        column,
+                        reason: String::from("expect a target before `:`"),
+                    });
+                }
+                parser.after_colon = true;
+            }
+            _ => parser.word.push(c),
+        }
+    }
+
+    parser.end_rule()?;
+
+    Ok(parser.prerequisites)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Vec<String> {
+        parse_depfile(source).unwrap()
+    }
+
+    #[test]
+    fn escapes_in_paths() {
+        assert_eq!(
+            parse("a.o: my\\ file.c dir\\#1/x.h cost$$.h\n"),
+            ["my file.c", "dir#1/x.h", "cost$.h"]
+        );
+    }
+
+    #[test]
+    fn windows_drive_colons() {
+        assert_eq!(
+            parse("C:\\out\\a.o: C:\\src\\a.c C:\\include\\b.h\n"),
+            ["C:\\src\\a.c", "C:\\include\\b.h"]
+        );
+    }
+
+    #[test]
+    fn joined_lines() {
+        assert_eq!(
+            parse("a.o: a.c \\\n  b.h \\\n  c.h\n"),
+            ["a.c", "b.h", "c.h"]
+        );
+        // the line ends of Windows
+        assert_eq!(parse("a.o: a.c \\\r\n  b.h\r\n"), ["a.c", "b.h"]);
+    }
+
+    #[test]
+    fn rules_and_comments() {
+        let source = "# written by gcc\na.o b.o: common.h a.c\nb.o: b.c common.h\ncommon.h:\n";
+
+        // without repeats, and the targets are ignored
+        assert_eq!(parse(source), ["common.h", "a.c", "b.c"]);
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn missing_colon() {
+        let err = parse_depfile("a.o: a.c\nb.o b.c\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 1));
+        assert_eq!(err.reason, "expect `:` after the targets of a rule");
+
+        // without a line end too
+        let err = parse_depfile("a.o a.c").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 1));
+
+        let err = parse_depfile("a.o: a.c\n  : b.c\n").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 3));
+        assert_eq!(err.reason, "expect a target before `:`");
+    }
+}
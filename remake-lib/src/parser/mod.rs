@@ -5,13 +5,31 @@ use ahash::AHashMap;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 
+pub mod depfile;
+pub mod text;
+
 pub struct ParseOption {}
 
 pub struct ParsedRemake {
     pub targets: AHashMap<Arc<String>, Arc<Target>>,
+    /// The names that were defined more than once. Only the first definition is kept in `targets`.
+    /// See `graph::validate`.
+    pub duplicates: Vec<Arc<String>>,
+    /// The depths of the pools, except the `console` pool. See docs/standard_4.md.
+    pub pools: AHashMap<String, u64>,
 }
 
-struct ByteReader<'a> {
+/// The smallest size in bytes of a string. That is, its length field.
+const MIN_STRING_SIZE: usize = 8;
+/// The smallest size in bytes of a command.
+/// executable + arguments + ignore errors + environments + working directory.
+/// The commands of later versions are larger.
+const MIN_COMMAND_SIZE: usize = MIN_STRING_SIZE + 8 + 1 + 8 + MIN_STRING_SIZE;
+/// The smallest size in bytes of a target. name + dependences + commands.
+/// The targets of later versions are larger.
+const MIN_TARGET_SIZE: usize = MIN_STRING_SIZE + 8 + 8;
+
+pub(crate) struct ByteReader<'a> {
     source: &'a [u8],
     length: usize,
     index: usize,
@@ -19,376 +37,364 @@ struct ByteReader<'a> {
 
 impl<'a> ByteReader<'a> {
     pub fn new(byte_array: &'a [u8]) -> ByteReader<'a> {
-        return ByteReader {
+        ByteReader {
             source: byte_array,
             length: byte_array.len(),
             index: 0,
-        };
+        }
+    }
+
+    pub fn position(&self) -> usize {
+        self.index
     }
 
-    pub fn read(&mut self, size: usize) -> Option<&'a [u8]> {
-        let end = self.index + size;
+    pub fn remaining(&self) -> usize {
+        self.length - self.index
+    }
 
-        if end >= self.length {
-            return None;
+    pub fn read(&mut self, size: usize) -> Result<&'a [u8], ParseError> {
+        if size > self.remaining() {
+            return Err(ParseError::new(
+                (self.index as u64, self.length as u64),
+                format!(
+                    "Exceptional End-Of-File. Need {} bytes but only {} bytes left. The build file may be broken.",
+                    size,
+                    self.remaining()
+                ),
+            ));
         }
 
         let buf = &self.source[self.index..(self.index + size)];
 
         self.index += size;
 
-        return Some(buf);
+        Ok(buf)
     }
 
-    fn read_some<T, F>(&mut self, size: usize, func: F) -> Option<T>
-    where
-        F: Fn(&'a [u8]) -> T,
-    {
-        let result = self.read(size);
-
-        if result.is_none() {
-            return None;
-        } else {
-            return Some(func(result.unwrap()));
-        }
+    pub fn read_u64(&mut self) -> Result<u64, ParseError> {
+        let read_bytes = self.read(8)?;
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(read_bytes);
+        Ok(u64::from_le_bytes(buf))
     }
 
-    pub fn read_u64(&mut self) -> Option<u64> {
-        return self.read_some(8, |read_bytes: &[u8]| -> u64 {
-            u64::from_le_bytes(read_bytes.try_into().unwrap())
-        });
+    pub fn read_boolean(&mut self) -> Result<bool, ParseError> {
+        Ok(self.read(1)?[0] != 0u8)
     }
 
-    pub fn read_boolean(&mut self) -> Option<bool> {
-        return self.read_some(1, |read_bytes: &[u8]| -> bool {
-            *read_bytes.get(0).unwrap() != 0u8
-        });
-    }
-
-    pub fn read_string(&mut self) -> Option<&'a str> {
-        let length = self.read_u64();
-
-        if length.is_none() {
-            return None;
+    /// Read a length field. Fail if the items can not fit in the rest bytes,
+    /// so the length is safe to allocate for.
+    pub fn read_length(&mut self, item_size: usize, what: &str) -> Result<usize, ParseError> {
+        let begin = self.index;
+        let length = self.read_u64()?;
+
+        let max = (self.remaining() / item_size) as u64;
+
+        if length > max {
+            return Err(ParseError::new(
+                self.span_from(begin),
+                format!(
+                    "the {} length {} exceeds the remaining {} bytes",
+                    what,
+                    length,
+                    self.remaining()
+                ),
+            ));
         }
 
-        return self.read_some(
-            usize::try_from(length.unwrap()).unwrap(),
-            |read_bytes: &'a [u8]| -> &'a str { std::str::from_utf8(read_bytes).unwrap() },
-        );
+        // no more than the remaining bytes, so it fits in usize
+        Ok(length as usize)
     }
 
-    pub fn get_span(&self) -> (u64, u64) {
-        return ((self.index - 1) as u64, self.index as u64);
+    pub fn read_string(&mut self) -> Result<&'a str, ParseError> {
+        let length = self.read_length(1, "string")?;
+        let begin = self.index;
+        let read_bytes = self.read(length)?;
+
+        std::str::from_utf8(read_bytes).map_err(|err| {
+            ParseError::new(
+                self.span_from(begin),
+                format!("the string is not valid UTF-8:{}", err),
+            )
+        })
+    }
+
+    /// Get the span from `begin` to the current index.
+    pub fn span_from(&self, begin: usize) -> (u64, u64) {
+        (begin as u64, self.index as u64)
     }
 
     pub fn is_end(&self) -> bool {
         self.index == self.length
     }
 
-    pub fn read_string_array(&mut self) -> Option<Arc<Vec<String>>> {
-        let length = self.read_u64();
-
-        if length.is_none() {
-            return None;
-        }
-
-        let length = length.unwrap();
+    pub fn read_string_array(&mut self) -> Result<Arc<Vec<String>>, ParseError> {
+        let length = self.read_length(MIN_STRING_SIZE, "string array")?;
 
-        let mut vecs = Vec::<String>::with_capacity(usize::try_from(length).unwrap());
+        let mut vecs = Vec::<String>::with_capacity(length);
 
         for _ in 0..length {
-            let read = self.read_string();
-
-            if read.is_none() {
-                return None;
-            }
-
-            vecs.push(String::from(read.unwrap()))
+            vecs.push(String::from(self.read_string()?))
         }
 
-        return Some(Arc::new(vecs));
+        Ok(Arc::new(vecs))
     }
 
-    pub fn read_string_map(&mut self) -> Option<AHashMap<String, String>> {
-        let length = self.read_u64();
-
-        if length.is_none() {
-            return None;
-        }
+    pub fn read_string_map(&mut self) -> Result<AHashMap<String, String>, ParseError> {
+        let length = self.read_length(MIN_STRING_SIZE * 2, "map")?;
 
-        let length = length.unwrap();
-
-        let mut map = AHashMap::<String, String>::with_capacity(usize::try_from(length).unwrap());
+        let mut map = AHashMap::<String, String>::with_capacity(length);
 
         for _ in 0..length {
-            let k = self.read_string();
-            let v = self.read_string();
-
-            if k.is_none() || v.is_none() {
-                return None;
-            }
+            let k = self.read_string()?;
+            let v = self.read_string()?;
 
-            map.insert(String::from(k.unwrap()), String::from(v.unwrap()));
+            map.insert(String::from(k), String::from(v));
         }
-        return Some(map);
+        Ok(map)
     }
 }
 
 /// read a command
-fn parse_command<'a>(reader: &mut ByteReader<'a>) -> Option<CommandsRunable> {
+fn parse_command(reader: &mut ByteReader<'_>, version: u64) -> Result<CommandsRunable, ParseError> {
     // read program name
-    let name = reader.read_string();
-    let args = reader.read_string_array();
-    let ignore_errors = reader.read_boolean();
-    let envs = reader.read_string_map();
-    let cwd = reader.read_string();
-
-    if name.is_none()
-        || args.is_none()
-        || ignore_errors.is_none()
-        || envs.is_none()
-        || cwd.is_none()
-    {
-        return None;
-    }
+    let name = reader.read_string()?;
+    let args = reader.read_string_array()?;
+    let ignore_errors = reader.read_boolean()?;
+    let envs = reader.read_string_map()?;
+    let cwd = reader.read_string()?;
+
+    // read depfile. since version 2
+    let depfile = if version >= 2 {
+        Some(reader.read_string()?)
+            .filter(|d| !d.is_empty())
+            .map(String::from)
+    } else {
+        None
+    };
 
-    return Some(CommandsRunable {
+    Ok(CommandsRunable {
         command: Arc::new(Command {
-            executable: Arc::new(String::from(name.unwrap())),
-            arguments: args.unwrap().to_vec(),
-            ignore_error: AtomicBool::new(ignore_errors.unwrap()),
-            environments: std::sync::RwLock::new(envs.unwrap()),
-            work_dir: Arc::new(String::from(cwd.unwrap())),
+            executable: Arc::new(String::from(name)),
+            arguments: args.to_vec(),
+            ignore_error: AtomicBool::new(ignore_errors),
+            environments: std::sync::RwLock::new(envs),
+            work_dir: Arc::new(String::from(cwd)),
+            depfile,
         }),
-    });
+    })
 }
 
 /// read a target
-fn parse_target<'a>(reader: &mut ByteReader<'a>) -> Option<Arc<Target>> {
+fn parse_target(reader: &mut ByteReader<'_>, version: u64) -> Result<Arc<Target>, ParseError> {
     // reader target name
-    let name = reader.read_string();
-
-    if name.is_none() {
-        return None;
-    }
-    let name = name.unwrap();
+    let name = reader.read_string()?;
 
     // read the dependences
-    let dependences = reader.read_string_array();
-
-    if dependences.is_none() {
-        return None;
-    }
-    let dependences = dependences.unwrap();
+    let dependences = reader.read_string_array()?;
 
     // read commands
-    let command_count = reader.read_u64();
-    if command_count.is_none() {
-        return None;
-    }
-    let command_count = command_count.unwrap();
+    let command_count = reader.read_length(MIN_COMMAND_SIZE, "command array")?;
 
-    let mut commands =
-        Vec::<CommandsRunable>::with_capacity(usize::try_from(command_count).unwrap());
+    let mut commands = Vec::<CommandsRunable>::with_capacity(command_count);
 
     for _ in 0..command_count {
-        let cmd = parse_command(reader);
-
-        if cmd.is_none() {
-            return None;
-        } else {
-            commands.push(cmd.unwrap());
-        }
+        commands.push(parse_command(reader, version)?);
     }
 
-    let commands = commands;
+    // read inputs and outputs. since version 1
+    let (inputs, outputs) = if version >= 1 {
+        (reader.read_string_array()?, reader.read_string_array()?)
+    } else {
+        (Arc::new(Vec::new()), Arc::new(Vec::new()))
+    };
+
+    // read pool. since version 3
+    let pool = if version >= 3 {
+        Some(reader.read_string()?)
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+    } else {
+        None
+    };
 
-    return Some(Arc::new(Target {
+    Ok(Arc::new(Target {
         name: Arc::new(String::from(name)),
-        dependences: dependences,
+        dependences,
         commands: Arc::new(commands),
-    }));
+        inputs,
+        outputs,
+        pool,
+    }))
 }
 
-fn parse_prefix<'a>(reader: &mut ByteReader<'a>) -> Option<ParseError> {
-    let taken = reader.read(6);
-
-    if taken.is_none() {
-        return Some(ParseError::from_exceptional_eof());
-    }
+fn parse_prefix(reader: &mut ByteReader<'_>) -> Result<(), ParseError> {
+    let taken = reader.read(6)?;
 
-    let taken = taken.unwrap();
-
-    if *taken.get(0).unwrap() != b'r'
-        || *taken.get(1).unwrap() != b'e'
-        || *taken.get(2).unwrap() != b'm'
-        || *taken.get(3).unwrap() != b'a'
-        || *taken.get(4).unwrap() != b'k'
-        || *taken.get(5).unwrap() != b'e'
-    {
-        return Some(ParseError {
-            source: None,
-            source_span: Some((0, 6)),
-            reason: Some(String::from("the bytes do not start with `remake`")),
-        });
+    if taken != b"remake" {
+        return Err(ParseError::new(
+            (0, 6),
+            String::from("the bytes do not start with `remake`"),
+        ));
     }
 
-    return None;
+    Ok(())
 }
 
-fn parse_platform<'a>(reader: &mut ByteReader<'a>) -> Option<ParseError> {
-    let taken = reader.read_u64();
-
-    if taken.is_none() {
-        return Some(ParseError::from_exceptional_eof());
-    }
+fn parse_platform(reader: &mut ByteReader<'_>) -> Result<(), ParseError> {
+    let begin = reader.index;
+    let taken = reader.read_u64()?;
+    let span = reader.span_from(begin);
+
+    let platform = match Platform::try_from(taken) {
+        Ok(platform) => platform,
+        Err(_) => {
+            return Err(ParseError::new(
+                span,
+                format!(
+                    "the platform number {} is unknown. format may be broken.",
+                    taken
+                ),
+            ))
+        }
+    };
 
-    let p = crate::format::definition::Platform::try_from(taken.unwrap());
+    let supported = match platform {
+        Platform::Unix => cfg!(unix),
+        Platform::Freebsd => cfg!(target_os = "freebsd"),
+        Platform::Mac => cfg!(target_os = "macos"),
+        Platform::Window => cfg!(windows),
+    };
 
-    if p.is_err() {
-        return Some(ParseError {
-            source: None,
-            source_span: Some(reader.get_span()),
-            reason: Some(String::from(
-                "the platform number is unknown. format may be broken.",
-            )),
-        });
+    if !supported {
+        return Err(ParseError::new(
+            span,
+            format!("the platform {} is not supported by this remake", taken),
+        ));
     }
 
-    return match p.unwrap() {
-        Platform::Unix => {
-            cfg_if::cfg_if! {
-            if #[cfg(unix)] {
-                None
-            }
-            else{
-                Some(
-                    ParseError {
-                        source: None,
-                        source_span: Some(reader.get_span()),
-                        reason:  Some(String::from("the platform is not right"))
-                    }
-                )
-            }
-            }
-        }
-        Platform::Freebsd => {
-            panic!("no bsd from remake");
-        }
-        Platform::Mac => {
-            cfg_if::cfg_if! {
-                if #[cfg(target_os = "macos")] {
-                    None
-                }
-                else{
-                     Some(
-                        ParseError {
-                            source: None,
-                            source_span: Some(reader.get_span()),
-                            reason:  Some(String::from("the platform is not right"))
-                        }
-                    )
-                }
-            }
-        }
-        Platform::Window => {
-            cfg_if::cfg_if! {
-                if #[cfg(windows)] {
-                    None
-                }
-                else{
-                    Some(
-                        ParseError {
-                            source: None,
-                            source_span: Some(reader.get_span()),
-                            reason:  Some(String::from("the platform is not right"))
-                        }
-                    )
-                }
-            }
-        }
-    };
+    Ok(())
 }
 
-fn parse_version<'a>(reader: &mut ByteReader<'a>) -> Option<ParseError> {
-    let version = reader.read_u64();
-
-    if version.is_none() {
-        return Some(ParseError::from_exceptional_eof());
-    } else {
-        if env!("CARGO_PKG_VERSION_MAJOR").parse::<u64>().unwrap() != version.unwrap() {
-            return Some(ParseError {
-                source: None,
-                source_span: Some(reader.get_span()),
-                reason: Some(String::from("the version is not match")),
-            });
-        }
+fn parse_version(reader: &mut ByteReader<'_>) -> Result<u64, ParseError> {
+    let begin = reader.index;
+    let version = reader.read_u64()?;
+
+    if version > crate::format::definition::VERSION {
+        return Err(ParseError::new(
+            reader.span_from(begin),
+            format!(
+                "the version {} is not match. Expect {} or lower",
+                version,
+                crate::format::definition::VERSION
+            ),
+        ));
     }
-    return None;
+
+    Ok(version)
 }
 
 /// parse bytes
+///
+/// Never panic. Any malformed input is reported as a `ParseError`.
 pub fn parse_from_bytes(bytes_vec: Vec<u8>) -> Result<ParsedRemake, ParseError> {
     let bytes = bytes_vec.into_boxed_slice();
     let bytes_ref = &*bytes;
     let mut reader = ByteReader::new(bytes_ref);
 
     // 检查remake前缀
-    let prefix = parse_prefix(&mut reader);
-
-    if prefix.is_some() {
-        return Err(prefix.unwrap());
-    }
+    parse_prefix(&mut reader)?;
 
     // 检查平台
-    let platform = parse_platform(&mut reader);
-
-    if platform.is_some() {
-        return Err(platform.unwrap());
-    }
+    parse_platform(&mut reader)?;
 
     // 解析version
-    let version = parse_version(&mut reader);
-
-    if version.is_some() {
-        return Err(version.unwrap());
-    }
+    let version = parse_version(&mut reader)?;
 
     // 获取targets数量
-    let target_count = reader.read_u64();
+    let target_count = reader.read_length(MIN_TARGET_SIZE, "target array")?;
 
-    if target_count.is_none() {
-        return Err(ParseError::from_exceptional_eof());
-    }
-
-    let target_count = target_count.unwrap();
+    let mut targets: AHashMap<Arc<String>, Arc<Target>> = AHashMap::with_capacity(target_count);
 
-    let mut targets: AHashMap<Arc<String>, Arc<Target>> =
-        AHashMap::with_capacity(usize::try_from(target_count).unwrap());
+    let mut duplicates: Vec<Arc<String>> = Vec::new();
 
     // 读取
     for _ in 0..target_count {
-        let target = parse_target(&mut reader);
+        let target = parse_target(&mut reader, version)?;
 
-        if target.is_none() {
-            return Err(ParseError::from_exceptional_eof());
+        if targets.contains_key(&target.name) {
+            duplicates.push(target.name.clone());
         } else {
-            let target = target.unwrap();
             targets.insert(target.name.clone(), target);
         }
     }
 
+    // read pools. since version 3
+    let mut pools: AHashMap<String, u64> = AHashMap::new();
+
+    if version >= 3 {
+        // name + depth
+        let pool_count = reader.read_length(MIN_STRING_SIZE + 8, "pool array")?;
+
+        for _ in 0..pool_count {
+            let begin = reader.index;
+            let name = reader.read_string()?;
+            let depth = reader.read_u64()?;
+
+            if pools.insert(String::from(name), depth).is_some() {
+                return Err(ParseError::new(
+                    reader.span_from(begin),
+                    format!("the pool `{}` is defined more than once", name),
+                ));
+            }
+        }
+    }
+
     // Check it is end
     if !reader.is_end() {
-        return Err(ParseError {
-            source: None,
-            source_span: Some(reader.get_span()),
-            reason: Some(String::from(
-                "All content has read but there are some bytes left",
-            )),
-        });
+        return Err(ParseError::new(
+            (reader.index as u64, reader.length as u64),
+            String::from("All content has read but there are some bytes left"),
+        ));
     }
 
-    Ok(ParsedRemake { targets })
+    Ok(ParsedRemake {
+        targets,
+        duplicates,
+        pools,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Every input that the fuzzer found interesting. See fuzz/regression.
+    ///
+    /// The `valid_*` inputs parse, and the others are malformed, so they are errors.
+    #[test]
+    fn regression_corpus_never_panics() {
+        let corpus =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../fuzz/regression/parse_from_bytes");
+        let mut count = 0;
+
+        for entry in std::fs::read_dir(&corpus).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let bytes = std::fs::read(&path).unwrap();
+
+            let parsed = std::panic::catch_unwind(|| parse_from_bytes(bytes).is_ok());
+            let valid = name.starts_with("valid_")
+                || (name == "freebsd_platform" && cfg!(target_os = "freebsd"));
+
+            match parsed {
+                Ok(ok) => assert_eq!(ok, valid, "{} is parsed: {}", name, ok),
+                Err(_) => panic!("{} panicked", name),
+            }
+            count += 1;
+        }
+
+        assert!(count > 0, "no input in {}", corpus.display());
+    }
 }
@@ -0,0 +1,703 @@
+//! The text front-end. See docs/text_format.md.
+
+use crate::errors::SyntaxError;
+use crate::format::builder::{CommandBuilder, GraphBuilder, TargetBuilder};
+use crate::parser::ParsedRemake;
+use ahash::AHashMap;
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    /// A bare or quoted word. Quoted words are never keywords.
+    Word {
+        text: String,
+        quoted: bool,
+    },
+    OpenBrace,
+    CloseBrace,
+    Newline,
+    End,
+}
+
+struct Token {
+    kind: TokenKind,
+    line: usize,
+    column: usize,
+}
+
+struct Lexer<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            chars: source.chars().peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, reason: String) -> SyntaxError {
+        SyntaxError {
+            line: self.line,
+            column: self.column,
+            reason,
+        }
+    }
+
+    fn is_word_char(c: char) -> bool {
+        !(c.is_whitespace() || c == '{' || c == '}' || c == '"' || c == '#')
+    }
+
+    fn read_quoted(&mut self) -> Result<String, SyntaxError> {
+        let mut text = String::new();
+        let unterminated = SyntaxError {
+            line: self.line,
+            column: self.column,
+            reason: String::from("unterminated string"),
+        };
+
+        // skip the open quote
+        self.bump();
+
+        loop {
+            match self.bump() {
+                None => return Err(unterminated),
+                Some('"') => return Ok(text),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        Some(other) => {
+                            return Err(self.error(format!("unknown escape `\\{}`", other)))
+                        }
+                        None => return Err(unterminated),
+                    };
+                    text.push(escaped);
+                }
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    pub fn next_token(&mut self) -> Result<Token, SyntaxError> {
+        // skip blanks and comments
+        loop {
+            match self.chars.peek() {
+                Some('#') => {
+                    while !matches!(self.chars.peek(), None | Some('\n')) {
+                        self.bump();
+                    }
+                }
+                Some(c) if *c != '\n' && c.is_whitespace() => {
+                    self.bump();
+                }
+                _ => break,
+            }
+        }
+
+        let line = self.line;
+        let column = self.column;
+
+        let kind = match self.chars.peek() {
+            None => TokenKind::End,
+            Some('\n') => {
+                self.bump();
+                TokenKind::Newline
+            }
+            Some('{') => {
+                self.bump();
+                TokenKind::OpenBrace
+            }
+            Some('}') => {
+                self.bump();
+                TokenKind::CloseBrace
+            }
+            Some('"') => TokenKind::Word {
+                text: self.read_quoted()?,
+                quoted: true,
+            },
+            Some(_) => {
+                let mut text = String::new();
+                while let Some(c) = self.chars.peek() {
+                    if !Lexer::is_word_char(*c) {
+                        break;
+                    }
+                    text.push(*c);
+                    self.bump();
+                }
+                TokenKind::Word {
+                    text,
+                    quoted: false,
+                }
+            }
+        };
+
+        Ok(Token { kind, line, column })
+    }
+}
+
+/// The settings that apply to the following `run` statements of a target.
+struct CommandSettings {
+    work_dir: String,
+    environments: AHashMap<String, String>,
+    ignore_error: bool,
+    /// Only applies to the next `run` statement. With its line and column.
+    depfile: Option<(String, usize, usize)>,
+}
+
+struct TextParser<'a> {
+    lexer: Lexer<'a>,
+    current: Token,
+}
+
+impl<'a> TextParser<'a> {
+    pub fn new(source: &'a str) -> Result<TextParser<'a>, SyntaxError> {
+        let mut lexer = Lexer::new(source);
+        let current = lexer.next_token()?;
+        Ok(TextParser { lexer, current })
+    }
+
+    fn advance(&mut self) -> Result<Token, SyntaxError> {
+        let next = self.lexer.next_token()?;
+        Ok(std::mem::replace(&mut self.current, next))
+    }
+
+    fn error_at(token: &Token, reason: String) -> SyntaxError {
+        SyntaxError {
+            line: token.line,
+            column: token.column,
+            reason,
+        }
+    }
+
+    fn describe(kind: &TokenKind) -> String {
+        match kind {
+            TokenKind::Word { text, .. } => format!("`{}`", text),
+            TokenKind::OpenBrace => String::from("`{`"),
+            TokenKind::CloseBrace => String::from("`}`"),
+            TokenKind::Newline => String::from("end of line"),
+            TokenKind::End => String::from("end of file"),
+        }
+    }
+
+    fn skip_newlines(&mut self) -> Result<(), SyntaxError> {
+        while self.current.kind == TokenKind::Newline {
+            self.advance()?;
+        }
+        Ok(())
+    }
+
+    fn expect_word(&mut self, what: &str) -> Result<String, SyntaxError> {
+        match &self.current.kind {
+            TokenKind::Word { text, .. } => {
+                let text = text.clone();
+                self.advance()?;
+                Ok(text)
+            }
+            other => Err(TextParser::error_at(
+                &self.current,
+                format!("expect {} but found {}", what, TextParser::describe(other)),
+            )),
+        }
+    }
+
+    /// Read the rest words of a statement.
+    fn read_words(&mut self) -> Result<Vec<String>, SyntaxError> {
+        let mut words = Vec::new();
+        while let TokenKind::Word { text, .. } = &self.current.kind {
+            words.push(text.clone());
+            self.advance()?;
+        }
+        Ok(words)
+    }
+
+    /// A statement ends at a newline or the end of the target block.
+    fn end_statement(&mut self) -> Result<(), SyntaxError> {
+        match self.current.kind {
+            TokenKind::Newline => {
+                self.advance()?;
+                Ok(())
+            }
+            TokenKind::CloseBrace => Ok(()),
+            ref other => Err(TextParser::error_at(
+                &self.current,
+                format!(
+                    "expect end of line but found {}",
+                    TextParser::describe(other)
+                ),
+            )),
+        }
+    }
+
+    fn parse_statement(
+        &mut self,
+        mut target: TargetBuilder,
+        settings: &mut CommandSettings,
+    ) -> Result<TargetBuilder, SyntaxError> {
+        let keyword_token = self.advance()?;
+
+        let keyword = match &keyword_token.kind {
+            TokenKind::Word {
+                text,
+                quoted: false,
+            } => text.as_str(),
+            other => {
+                return Err(TextParser::error_at(
+                    &keyword_token,
+                    format!(
+                        "expect a statement but found {}",
+                        TextParser::describe(other)
+                    ),
+                ))
+            }
+        };
+
+        match keyword {
+            "deps" => {
+                for dependence in self.read_words()? {
+                    target = target.dependence(&dependence);
+                }
+            }
+            "inputs" => {
+                for input in self.read_words()? {
+                    target = target.input(&input);
+                }
+            }
+            "outputs" => {
+                for output in self.read_words()? {
+                    target = target.output(&output);
+                }
+            }
+            "pool" => {
+                target = target.pool(&self.expect_word("a pool name")?);
+            }
+            "depfile" => {
+                let path_token_line = self.current.line;
+                let path_token_column = self.current.column;
+                let path = self.expect_word("a depfile path")?;
+                settings.depfile = Some((path, path_token_line, path_token_column));
+            }
+            "cwd" => {
+                settings.work_dir = self.expect_word("a directory")?;
+            }
+            "env" => {
+                let pair_token_line = self.current.line;
+                let pair_token_column = self.current.column;
+                let pair = self.expect_word("`KEY=VALUE`")?;
+
+                match pair.split_once('=') {
+                    Some((key, value)) if !key.is_empty() => {
+                        settings
+                            .environments
+                            .insert(String::from(key), String::from(value));
+                    }
+                    _ => {
+                        return Err(SyntaxError {
+                            line: pair_token_line,
+                            column: pair_token_column,
+                            reason: format!("expect `KEY=VALUE` but found `{}`", pair),
+                        })
+                    }
+                }
+            }
+            "ignore-errors" => {
+                let value_token_line = self.current.line;
+                let value_token_column = self.current.column;
+                let value = self.expect_word("`yes` or `no`")?;
+
+                settings.ignore_error = match value.as_str() {
+                    "yes" | "true" => true,
+                    "no" | "false" => false,
+                    _ => {
+                        return Err(SyntaxError {
+                            line: value_token_line,
+                            column: value_token_column,
+                            reason: format!("expect `yes` or `no` but found `{}`", value),
+                        })
+                    }
+                };
+            }
+            "run" => {
+                let mut words = self.read_words()?.into_iter();
+
+                let executable = match words.next() {
+                    Some(executable) => executable,
+                    None => {
+                        return Err(TextParser::error_at(
+                            &self.current,
+                            String::from("expect a program after `run`"),
+                        ))
+                    }
+                };
+
+                let mut command = CommandBuilder::new(&executable)
+                    .arguments(words)
+                    .work_dir(&settings.work_dir)
+                    .ignore_error(settings.ignore_error);
+
+                if let Some((depfile, _, _)) = settings.depfile.take() {
+                    command = command.depfile(&depfile);
+                }
+
+                for (key, value) in settings.environments.iter() {
+                    command = command.environment(key, value);
+                }
+
+                target = target.command(command);
+            }
+            other => {
+                return Err(TextParser::error_at(
+                    &keyword_token,
+                    format!("unknown statement `{}`", other),
+                ))
+            }
+        }
+
+        self.end_statement()?;
+        Ok(target)
+    }
+
+    /// Parse `target NAME { ... }`
+    fn parse_target(&mut self) -> Result<(String, TargetBuilder), SyntaxError> {
+        let name = self.expect_word("a target name")?;
+
+        if self.current.kind != TokenKind::OpenBrace {
+            return Err(TextParser::error_at(
+                &self.current,
+                format!(
+                    "expect `{{` but found {}",
+                    TextParser::describe(&self.current.kind)
+                ),
+            ));
+        }
+        self.advance()?;
+
+        let mut target = TargetBuilder::new(&name);
+        let mut settings = CommandSettings {
+            work_dir: String::from("."),
+            environments: AHashMap::new(),
+            ignore_error: false,
+            depfile: None,
+        };
+
+        loop {
+            self.skip_newlines()?;
+
+            match self.current.kind {
+                TokenKind::CloseBrace => {
+                    if let Some((_, line, column)) = settings.depfile {
+                        return Err(SyntaxError {
+                            line,
+                            column,
+                            reason: String::from(
+                                "the depfile is not followed by a `run` statement",
+                            ),
+                        });
+                    }
+                    self.advance()?;
+                    return Ok((name, target));
+                }
+                TokenKind::End => {
+                    return Err(TextParser::error_at(
+                        &self.current,
+                        format!("the target `{}` is not closed by `}}`", name),
+                    ))
+                }
+                _ => {
+                    target = self.parse_statement(target, &mut settings)?;
+                }
+            }
+        }
+    }
+
+    /// Parse `pool NAME DEPTH`
+    fn parse_pool(&mut self, graph: &mut GraphBuilder) -> Result<(), SyntaxError> {
+        let name_token_line = self.current.line;
+        let name_token_column = self.current.column;
+        let name = self.expect_word("a pool name")?;
+
+        let depth_token_line = self.current.line;
+        let depth_token_column = self.current.column;
+        let depth = self.expect_word("the depth of the pool")?;
+
+        let depth: u64 = depth.parse().map_err(|_| SyntaxError {
+            line: depth_token_line,
+            column: depth_token_column,
+            reason: format!("expect a number but found `{}`", depth),
+        })?;
+
+        if let Err(err) = graph.pool(&name, depth) {
+            return Err(SyntaxError {
+                line: name_token_line,
+                column: name_token_column,
+                reason: err.reason.unwrap_or_default(),
+            });
+        }
+
+        match self.current.kind {
+            TokenKind::Newline | TokenKind::End => Ok(()),
+            ref other => Err(TextParser::error_at(
+                &self.current,
+                format!(
+                    "expect end of line but found {}",
+                    TextParser::describe(other)
+                ),
+            )),
+        }
+    }
+
+    pub fn parse(&mut self) -> Result<ParsedRemake, SyntaxError> {
+        let mut graph = GraphBuilder::new();
+        let mut positions: AHashMap<String, (usize, usize)> = AHashMap::new();
+
+        loop {
+            self.skip_newlines()?;
+
+            let token = self.advance()?;
+
+            match &token.kind {
+                TokenKind::End => break,
+                TokenKind::Word {
+                    text,
+                    quoted: false,
+                } if text == "target" => {
+                    let name_token_line = self.current.line;
+                    let name_token_column = self.current.column;
+                    let (name, target) = self.parse_target()?;
+
+                    if let Err(err) = graph.target(target) {
+                        return Err(SyntaxError {
+                            line: name_token_line,
+                            column: name_token_column,
+                            reason: err.reason.unwrap_or_default(),
+                        });
+                    }
+
+                    positions.insert(name, (name_token_line, name_token_column));
+                }
+                TokenKind::Word {
+                    text,
+                    quoted: false,
+                } if text == "pool" => {
+                    self.parse_pool(&mut graph)?;
+                }
+                other => {
+                    return Err(TextParser::error_at(
+                        &token,
+                        format!(
+                            "expect `target` or `pool` but found {}",
+                            TextParser::describe(other)
+                        ),
+                    ))
+                }
+            }
+        }
+
+        graph.build().map_err(|err| {
+            let (line, column) = err
+                .target
+                .as_ref()
+                .and_then(|name| positions.get(name))
+                .copied()
+                .unwrap_or((1, 1));
+
+            SyntaxError {
+                line,
+                column,
+                reason: err.reason.unwrap_or_default(),
+            }
+        })
+    }
+}
+
+/// Parse a text build file and lower it into the targets.
+pub fn parse_from_text(source: &str) -> Result<ParsedRemake, SyntaxError> {
+    TextParser::new(source)?.parse()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::writer::write_to_bytes;
+
+    const SOURCE: &str = r#"# a comment before everything
+pool link 2
+
+target app {
+    pool link   # a comment after a statement
+    deps lib
+    inputs main.c
+    outputs build/app
+    cwd build
+    env CC=gcc
+    run gcc -o app "../main.c" "with space" "quote\"and\\slash"
+
+    ignore-errors yes
+    run rm "tab\there"
+}
+
+target lib {
+    outputs libfoo.a
+    depfile foo.d
+    run gcc -MD -MF foo.d -c foo.c
+}
+
+target "shell" {
+    pool console
+    run sh
+}
+"#;
+
+    fn parse_error(source: &str) -> SyntaxError {
+        match parse_from_text(source) {
+            Ok(_) => panic!("`{}` is parsed", source),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn same_bytes_as_the_builder() {
+        let mut graph = GraphBuilder::new();
+        graph.pool("link", 2).unwrap();
+        graph
+            .target(
+                TargetBuilder::new("app")
+                    .pool("link")
+                    .dependence("lib")
+                    .input("main.c")
+                    .output("build/app")
+                    .command(
+                        CommandBuilder::new("gcc")
+                            .arguments(["-o", "app", "../main.c", "with space"])
+                            .argument("quote\"and\\slash")
+                            .work_dir("build")
+                            .environment("CC", "gcc"),
+                    )
+                    .command(
+                        CommandBuilder::new("rm")
+                            .argument("tab\there")
+                            .work_dir("build")
+                            .environment("CC", "gcc")
+                            .ignore_error(true),
+                    ),
+            )
+            .unwrap()
+            .target(
+                TargetBuilder::new("lib").output("libfoo.a").command(
+                    CommandBuilder::new("gcc")
+                        .arguments(["-MD", "-MF", "foo.d", "-c", "foo.c"])
+                        .depfile("foo.d"),
+                ),
+            )
+            .unwrap()
+            .target(
+                TargetBuilder::new("shell")
+                    .pool("console")
+                    .command(CommandBuilder::new("sh")),
+            )
+            .unwrap();
+
+        let parsed = parse_from_text(SOURCE).unwrap();
+
+        assert_eq!(write_to_bytes(&parsed), graph.build_bytes().unwrap());
+    }
+
+    #[test]
+    fn quoted_words_are_never_keywords() {
+        let err = parse_error("target a {\n    \"run\" echo\n}\n");
+
+        assert_eq!((err.line, err.column), (2, 5));
+        assert_eq!(err.reason, "expect a statement but found `run`");
+    }
+
+    #[test]
+    fn the_position_of_a_syntax_error() {
+        let cases = [
+            (
+                "target a {\n    bogus x\n}\n",
+                2,
+                5,
+                "unknown statement `bogus`",
+            ),
+            (
+                "target a {\n    run \"open\n}\n",
+                2,
+                9,
+                "unterminated string",
+            ),
+            ("pool link x\n", 1, 11, "expect a number but found `x`"),
+            ("pool link 2 3\n", 1, 13, "expect end of line but found `3`"),
+            (
+                "target a {\n    run echo\n",
+                3,
+                1,
+                "the target `a` is not closed by `}`",
+            ),
+            ("target a\n", 1, 9, "expect `{` but found end of line"),
+            (
+                "target a {\n    run\n}\n",
+                2,
+                8,
+                "expect a program after `run`",
+            ),
+            (
+                "target a {\n    env KEY\n}\n",
+                2,
+                9,
+                "expect `KEY=VALUE` but found `KEY`",
+            ),
+            (
+                "target a {\n    depfile a.d\n}\n",
+                2,
+                13,
+                "the depfile is not followed by a `run` statement",
+            ),
+            ("{\n", 1, 1, "expect `target` or `pool` but found `{`"),
+        ];
+
+        for (source, line, column, reason) in cases {
+            let err = parse_error(source);
+            assert_eq!(
+                (err.line, err.column, err.reason.as_str()),
+                (line, column, reason),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn the_position_of_a_graph_error() {
+        // at the name of the target
+        let err = parse_error("target a {\n    run echo\n}\ntarget b {\n    deps c\n}\n");
+
+        assert_eq!((err.line, err.column), (4, 8));
+        assert!(err.reason.contains("`c`"), "{}", err.reason);
+    }
+
+    #[test]
+    fn unknown_escape() {
+        let err = parse_error("target a {\n    run echo \"\\q\"\n}\n");
+
+        assert_eq!(err.line, 2);
+        assert_eq!(err.reason, "unknown escape `\\q`");
+    }
+}
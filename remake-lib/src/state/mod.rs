@@ -0,0 +1,545 @@
+//! The build state. It records what a target was executed with.
+//!
+//! A target is up to date when its commands and the contents of its inputs
+//! are the same as the last successful execution. The modified time is not used.
+//!
+//! It also records how long the targets took, so the scheduler can start the long ones first.
+
+use crate::errors::ParseError;
+use crate::executer::up_to_date::{self, Staleness};
+use crate::format::writer::{self, ByteWriter};
+use crate::format::Target;
+use crate::parser::{depfile, ByteReader};
+use ahash::AHashMap;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The version of the build state file.
+/// - 1: the first version
+/// - 2: records have implicit inputs
+/// - 3: the durations of the targets
+const STATE_VERSION: u64 = 3;
+
+/// What a target was executed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetRecord {
+    /// See `hash_command`.
+    pub command_hash: String,
+    /// The hashes of the inputs and the outputs of the dependences.
+    /// An input that does not exist has an empty hash.
+    pub inputs: AHashMap<String, String>,
+    /// The hashes of the inputs found in the depfiles of the commands.
+    /// They are only known after the target is executed.
+    pub implicit_inputs: AHashMap<String, String>,
+}
+
+impl TargetRecord {
+    /// Hash what a target will be executed with.
+    pub fn of(target: &Target, all_targets: &AHashMap<Arc<String>, Arc<Target>>) -> TargetRecord {
+        let mut record = TargetRecord {
+            command_hash: hash_command(target),
+            inputs: AHashMap::new(),
+            implicit_inputs: AHashMap::new(),
+        };
+
+        for input in up_to_date::all_inputs(target, all_targets) {
+            let hash = hash_file(input).unwrap_or_default();
+            record.inputs.insert(input.clone(), hash);
+        }
+
+        record
+    }
+
+    /// Read the depfiles of a target and hash the inputs in them.
+    /// Call this after the target was executed successfully.
+    ///
+    /// The paths in a depfile are relative to the work directory of its command.
+    pub fn read_depfiles(&mut self, target: &Target) -> Result<(), String> {
+        for command in target.commands.iter() {
+            let command = &command.command;
+
+            let depfile = match &command.depfile {
+                Some(depfile) => resolve(&command.work_dir, depfile),
+                None => continue,
+            };
+
+            let source = std::fs::read_to_string(&depfile)
+                .map_err(|err| format!("can not read the depfile `{}`:{}", depfile, err))?;
+
+            let inputs = depfile::parse_depfile(&source)
+                .map_err(|err| format!("can not parse the depfile `{}`:{}", depfile, err))?;
+
+            for input in inputs {
+                let input = resolve(&command.work_dir, &input);
+
+                if self.inputs.contains_key(&input) {
+                    continue;
+                }
+
+                let hash = hash_file(&input).unwrap_or_default();
+                self.implicit_inputs.insert(input, hash);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hash the record into one key. The same commands and inputs get the same key.
+    /// The implicit inputs are not included.
+    pub fn key(&self) -> String {
+        let mut bytes = ByteWriter::new();
+        bytes.write_string(&self.command_hash);
+        bytes.write_string_map(&self.inputs);
+
+        to_hex(&Sha256::digest(bytes.into_bytes()))
+    }
+}
+
+/// The result of `BuildState::check`.
+pub struct StateCheck {
+    /// None if the target is up to date.
+    pub staleness: Option<Staleness>,
+    /// What the target will be executed with.
+    /// Record it by `BuildState::record` after the target is executed successfully.
+    pub record: Option<TargetRecord>,
+}
+
+/// The records of the targets. It can be shared between the workers.
+pub struct BuildState {
+    records: Mutex<AHashMap<String, TargetRecord>>,
+    /// How long the targets took when they were executed successfully last time.
+    durations: Mutex<AHashMap<String, Duration>>,
+}
+
+/// Make a path relative to the work directory relative to the current directory.
+fn resolve(work_dir: &str, path: &str) -> String {
+    if work_dir == "." || Path::new(path).is_absolute() {
+        String::from(path)
+    } else {
+        Path::new(work_dir)
+            .join(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Hash the content of a file.
+pub fn hash_file(path: &str) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Hash the commands and the outputs of a target.
+/// The executables, arguments, environment variables, working directories and `IgnoreErrors` are hashed.
+pub fn hash_command(target: &Target) -> String {
+    let mut bytes = ByteWriter::new();
+
+    bytes.write_u64(target.commands.len() as u64);
+    for command in target.commands.iter() {
+        writer::write_command(&mut bytes, &command.command);
+    }
+    bytes.write_string_array(&target.outputs);
+
+    to_hex(&Sha256::digest(bytes.into_bytes()))
+}
+
+impl BuildState {
+    pub fn new() -> BuildState {
+        BuildState {
+            records: Mutex::new(AHashMap::new()),
+            durations: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Load the build state. A file that does not exist is an empty state.
+    pub fn load(path: &Path) -> Result<BuildState, ParseError> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(BuildState::new()),
+            Err(err) => {
+                return Err(ParseError {
+                    source: None,
+                    source_span: None,
+                    reason: Some(format!("can not read the build state:{}", err)),
+                })
+            }
+        };
+
+        BuildState::from_bytes(&bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<BuildState, ParseError> {
+        let mut reader = ByteReader::new(bytes);
+
+        if reader.read_string()? != "remake-state" {
+            return Err(ParseError::new(
+                reader.span_from(0),
+                String::from("the bytes are not a build state"),
+            ));
+        }
+
+        let begin = reader.position();
+        let version = reader.read_u64()?;
+        if version == 0 || version > STATE_VERSION {
+            return Err(ParseError::new(
+                reader.span_from(begin),
+                format!("the build state version {} is not supported", version),
+            ));
+        }
+
+        // name + command hash + inputs (+ implicit inputs)
+        let count = reader.read_length(8 + 8 + 8, "record array")?;
+        let mut records = AHashMap::with_capacity(count);
+
+        for _ in 0..count {
+            let name = String::from(reader.read_string()?);
+            let command_hash = String::from(reader.read_string()?);
+            let inputs = reader.read_string_map()?;
+            let implicit_inputs = if version >= 2 {
+                reader.read_string_map()?
+            } else {
+                AHashMap::new()
+            };
+
+            records.insert(
+                name,
+                TargetRecord {
+                    command_hash,
+                    inputs,
+                    implicit_inputs,
+                },
+            );
+        }
+
+        let mut durations = AHashMap::new();
+
+        if version >= 3 {
+            // name + milliseconds
+            let count = reader.read_length(8 + 8, "duration array")?;
+            durations.reserve(count);
+
+            for _ in 0..count {
+                let name = String::from(reader.read_string()?);
+                let millis = reader.read_u64()?;
+                durations.insert(name, Duration::from_millis(millis));
+            }
+        }
+
+        if !reader.is_end() {
+            return Err(ParseError::new(
+                (reader.position() as u64, bytes.len() as u64),
+                String::from("All content has read but there are some bytes left"),
+            ));
+        }
+
+        Ok(BuildState {
+            records: Mutex::new(records),
+            durations: Mutex::new(durations),
+        })
+    }
+
+    /// Save the build state. The file is replaced at once so a break never leaves a broken file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let records = self.records.lock().unwrap();
+
+        let mut names: Vec<&String> = records.keys().collect();
+        names.sort_unstable();
+
+        let mut bytes = ByteWriter::new();
+        bytes.write_string("remake-state");
+        bytes.write_u64(STATE_VERSION);
+        bytes.write_u64(names.len() as u64);
+
+        for name in names {
+            let record = &records[name];
+            bytes.write_string(name);
+            bytes.write_string(&record.command_hash);
+            bytes.write_string_map(&record.inputs);
+            bytes.write_string_map(&record.implicit_inputs);
+        }
+
+        let durations = self.durations.lock().unwrap();
+
+        let mut names: Vec<&String> = durations.keys().collect();
+        names.sort_unstable();
+
+        bytes.write_u64(names.len() as u64);
+
+        for name in names {
+            bytes.write_string(name);
+            bytes.write_u64(durations[name].as_millis() as u64);
+        }
+
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+
+        std::fs::write(&temporary, bytes.into_bytes())?;
+        std::fs::rename(&temporary, path)
+    }
+
+    /// Get the record of a target.
+    pub fn get(&self, target: &str) -> Option<TargetRecord> {
+        self.records.lock().unwrap().get(target).cloned()
+    }
+
+    /// Record a target that was executed successfully.
+    pub fn record(&self, target: &str, record: TargetRecord) {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(String::from(target), record);
+    }
+
+    /// Get how long a target took last time.
+    pub fn duration(&self, target: &str) -> Option<Duration> {
+        self.durations.lock().unwrap().get(target).copied()
+    }
+
+    /// Record how long a target took. Only the targets executed successfully should be recorded,
+    /// because an up-to-date or failed one tells nothing about how long it takes.
+    pub fn record_duration(&self, target: &str, duration: Duration) {
+        self.durations
+            .lock()
+            .unwrap()
+            .insert(String::from(target), duration);
+    }
+
+    /// Forget a target, so it will be executed next time.
+    pub fn forget(&self, target: &str) {
+        self.records.lock().unwrap().remove(target);
+    }
+
+    /// Check if a target need be executed by the hashes.
+    pub fn check(
+        &self,
+        target: &Target,
+        all_targets: &AHashMap<Arc<String>, Arc<Target>>,
+    ) -> StateCheck {
+        if target.outputs.is_empty() {
+            return StateCheck {
+                staleness: Some(Staleness::NoOutputs),
+                record: None,
+            };
+        }
+
+        let record = TargetRecord::of(target, all_targets);
+
+        // the hash of a file is never empty
+        let missing_input = up_to_date::all_inputs(target, all_targets)
+            .find(|input| record.inputs[*input].is_empty())
+            .cloned();
+
+        let staleness = self.compare(target, &record, missing_input);
+
+        StateCheck {
+            staleness,
+            record: Some(record),
+        }
+    }
+
+    fn compare(
+        &self,
+        target: &Target,
+        current: &TargetRecord,
+        missing_input: Option<String>,
+    ) -> Option<Staleness> {
+        if let Some(input) = missing_input {
+            return Some(Staleness::MissingInput(input));
+        }
+
+        for output in target.outputs.iter() {
+            if !Path::new(output).exists() {
+                return Some(Staleness::MissingOutput(output.clone()));
+            }
+        }
+
+        let last = match self.get(&target.name) {
+            Some(last) => last,
+            None => return Some(Staleness::NoRecord),
+        };
+
+        if last.command_hash != current.command_hash {
+            return Some(Staleness::CommandChanged);
+        }
+
+        let mut inputs: Vec<&String> = current.inputs.keys().collect();
+        inputs.sort_unstable();
+
+        for input in inputs {
+            if last.inputs.get(input) != current.inputs.get(input) {
+                return Some(Staleness::InputChanged(input.clone()));
+            }
+        }
+
+        // an input was removed from the target
+        let mut removed: Vec<&String> = last
+            .inputs
+            .keys()
+            .filter(|input| !current.inputs.contains_key(*input))
+            .collect();
+        removed.sort_unstable();
+
+        if let Some(input) = removed.first() {
+            return Some(Staleness::InputChanged((*input).clone()));
+        }
+
+        // the inputs found in the depfiles last time
+        let mut implicit_inputs: Vec<(&String, &String)> = last.implicit_inputs.iter().collect();
+        implicit_inputs.sort_unstable();
+
+        for (input, hash) in implicit_inputs {
+            if hash_file(input).unwrap_or_default() != *hash {
+                return Some(Staleness::InputChanged(input.clone()));
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for BuildState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::builder::{CommandBuilder, GraphBuilder, TargetBuilder};
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("remake-state-test-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn graph(input: &str, output: &str, argument: &str) -> AHashMap<Arc<String>, Arc<Target>> {
+        let mut graph = GraphBuilder::new();
+        graph
+            .target(
+                TargetBuilder::new("out")
+                    .input(input)
+                    .output(output)
+                    .command(CommandBuilder::new("touch").argument(argument)),
+            )
+            .unwrap();
+        graph.build().unwrap().targets
+    }
+
+    #[test]
+    fn save_and_load() {
+        let directory = directory("save");
+        let path = directory.join("state");
+
+        // a missing file is an empty state
+        assert!(BuildState::load(&path).unwrap().get("app").is_none());
+
+        let record = TargetRecord {
+            command_hash: String::from("command"),
+            inputs: AHashMap::from_iter([(String::from("main.c"), String::from("hash"))]),
+            implicit_inputs: AHashMap::from_iter([(String::from("util.h"), String::new())]),
+        };
+        let state = BuildState::new();
+        state.record("app", record.clone());
+        state.record("forgotten", record.clone());
+        state.forget("forgotten");
+        state.record_duration("app", Duration::from_millis(1500));
+        state.save(&path).unwrap();
+
+        let loaded = BuildState::load(&path);
+        fs::remove_dir_all(&directory).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.get("app"), Some(record));
+        assert_eq!(loaded.get("forgotten"), None);
+        assert_eq!(loaded.duration("app"), Some(Duration::from_millis(1500)));
+    }
+
+    fn state_bytes(version: u64) -> Vec<u8> {
+        let mut bytes = ByteWriter::new();
+        bytes.write_string("remake-state");
+        bytes.write_u64(version);
+        bytes.write_u64(1);
+        bytes.write_string("app");
+        bytes.write_string("command");
+        bytes.write_string_map(&AHashMap::new());
+        bytes.into_bytes()
+    }
+
+    #[test]
+    fn the_versions() {
+        // the version 1 has no implicit inputs and no durations
+        let state = BuildState::from_bytes(&state_bytes(1)).unwrap();
+        assert_eq!(state.get("app").unwrap().command_hash, "command");
+        assert!(state.get("app").unwrap().implicit_inputs.is_empty());
+
+        // the caller starts with an empty state then
+        assert!(BuildState::from_bytes(&state_bytes(STATE_VERSION + 1)).is_err());
+        assert!(BuildState::from_bytes(&state_bytes(0)).is_err());
+        assert!(BuildState::from_bytes(b"not a state").is_err());
+
+        let mut bytes = state_bytes(1);
+        bytes.push(0);
+        assert!(BuildState::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn check_the_hashes() {
+        let directory = directory("check");
+        let input = directory.join("in");
+        let output = directory.join("out");
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        fs::write(input, "first").unwrap();
+        fs::write(output, "").unwrap();
+
+        let targets = graph(input, output, "a");
+        let target = targets.values().next().unwrap();
+        let state = BuildState::new();
+
+        let check = state.check(target, &targets);
+        let first = check.staleness;
+        state.record(&target.name, check.record.unwrap());
+        let recorded = state.check(target, &targets).staleness;
+
+        // by the content, writing it back makes it up to date again
+        fs::write(input, "other").unwrap();
+        let input_changed = state.check(target, &targets).staleness;
+        fs::write(input, "first").unwrap();
+        let changed_back = state.check(target, &targets).staleness;
+
+        let changed = graph(input, output, "b");
+        let command_changed = state
+            .check(changed.values().next().unwrap(), &changed)
+            .staleness;
+
+        fs::remove_file(output).unwrap();
+        let missing_output = state.check(target, &targets).staleness;
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(first, Some(Staleness::NoRecord));
+        assert_eq!(recorded, None);
+        assert_eq!(
+            input_changed,
+            Some(Staleness::InputChanged(String::from(input)))
+        );
+        assert_eq!(changed_back, None);
+        assert_eq!(command_changed, Some(Staleness::CommandChanged));
+        assert_eq!(
+            missing_output,
+            Some(Staleness::MissingOutput(String::from(output)))
+        );
+    }
+}
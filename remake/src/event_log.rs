@@ -0,0 +1,228 @@
+//! Write the build events as JSON Lines. See docs/event_log.md.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use remake_lib::errors::RuntimeError;
+use remake_lib::executer::observer::BuildObserver;
+use remake_lib::executer::scheduler::TargetState;
+use remake_lib::executer::up_to_date::Staleness;
+use remake_lib::executer::BuildReport;
+use remake_lib::format::{Command, CommandRun};
+use serde_json::{json, Value};
+
+/// Write one JSON object per line for every event.
+///
+/// The captured output of a target is written into a file in `<path>.outputs/`,
+/// and the event has the path of it.
+pub struct EventLog {
+    file: Mutex<BufWriter<File>>,
+    outputs: PathBuf,
+    /// Reset when the build starts, so the loading before it is not counted.
+    begin: Mutex<Instant>,
+    /// For the names of the output files.
+    counter: AtomicU64,
+}
+
+fn argv(command: &Command) -> Vec<&str> {
+    std::iter::once(command.executable.as_str())
+        .chain(command.arguments.iter().map(|a| a.as_str()))
+        .collect()
+}
+
+fn names(targets: &[Arc<String>]) -> Vec<&str> {
+    targets.iter().map(|t| t.as_str()).collect()
+}
+
+fn state_name(state: TargetState) -> &'static str {
+    match state {
+        TargetState::Waiting => "waiting",
+        TargetState::Ready => "ready",
+        TargetState::Running => "running",
+        TargetState::Succeeded => "succeeded",
+        TargetState::UpToDate => "up_to_date",
+        TargetState::Restored => "restored",
+        TargetState::Failed => "failed",
+        TargetState::Skipped => "skipped",
+    }
+}
+
+fn reason(error: &RuntimeError) -> String {
+    error.reason.clone().unwrap_or_else(|| error.to_string())
+}
+
+impl EventLog {
+    /// Create the log file. An old one is replaced.
+    pub fn create(path: &str) -> io::Result<EventLog> {
+        let file = File::create(path)?;
+
+        let mut outputs = Path::new(path).as_os_str().to_owned();
+        outputs.push(".outputs");
+
+        Ok(EventLog {
+            file: Mutex::new(BufWriter::new(file)),
+            outputs: PathBuf::from(outputs),
+            begin: Mutex::new(Instant::now()),
+            counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Write an event with its name and the seconds since the build began.
+    fn write(&self, event: &str, mut fields: Value) {
+        fields["event"] = json!(event);
+        fields["time"] = json!(self.begin.lock().unwrap().elapsed().as_secs_f64());
+
+        let mut file = self.file.lock().unwrap();
+
+        // a broken log never breaks the build
+        let _ = serde_json::to_writer(&mut *file, &fields);
+        let _ = file.write_all(b"\n");
+        let _ = file.flush();
+    }
+
+    /// Write an output into a new file. The name is made of a number and the target name.
+    fn write_output(&self, target: &str, output: &[u8]) -> io::Result<PathBuf> {
+        let name: String = target
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+
+        let path = self.outputs.join(format!(
+            "{}-{}.log",
+            self.counter.fetch_add(1, Ordering::SeqCst),
+            name
+        ));
+
+        fs::create_dir_all(&self.outputs)?;
+        fs::write(&path, output)?;
+
+        Ok(path)
+    }
+}
+
+impl BuildObserver for EventLog {
+    fn build_started(&self, targets: &[String]) {
+        *self.begin.lock().unwrap() = Instant::now();
+        self.write("build_started", json!({ "targets": targets }));
+    }
+
+    fn target_scheduled(&self, worker: u64, target: &str) {
+        self.write(
+            "target_scheduled",
+            json!({ "worker": worker, "target": target }),
+        );
+    }
+
+    fn target_started(&self, worker: u64, target: &str, staleness: &Staleness) {
+        self.write(
+            "target_started",
+            json!({ "worker": worker, "target": target, "reason": staleness.to_string() }),
+        );
+    }
+
+    fn command_spawned(&self, worker: u64, target: &str, command: &Command, pid: u32) {
+        self.write(
+            "command_spawned",
+            json!({
+                "worker": worker,
+                "target": target,
+                "argv": argv(command),
+                "work_dir": command.work_dir.as_str(),
+                "pid": pid,
+            }),
+        );
+    }
+
+    fn command_exited(&self, worker: u64, target: &str, command: &Command, run: &CommandRun) {
+        self.write(
+            "command_exited",
+            json!({
+                "worker": worker,
+                "target": target,
+                "argv": argv(command),
+                "pid": run.pid,
+                "exit_code": run.exit_code,
+                "wall_time": run.duration.as_secs_f64(),
+                "error": run.result.as_ref().err().map(reason),
+            }),
+        );
+    }
+
+    fn target_output(&self, worker: u64, target: &str, output: &[u8]) {
+        let (path, error) = match self.write_output(target, output) {
+            Ok(path) => (Some(path.to_string_lossy().into_owned()), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+
+        self.write(
+            "target_output",
+            json!({
+                "worker": worker,
+                "target": target,
+                "output_path": path,
+                "bytes": output.len(),
+                "error": error,
+            }),
+        );
+    }
+
+    fn target_finished(&self, worker: u64, target: &str, state: TargetState) {
+        self.write(
+            "target_finished",
+            json!({ "worker": worker, "target": target, "state": state_name(state) }),
+        );
+    }
+
+    fn target_failed(&self, worker: u64, target: &str, error: &RuntimeError) {
+        self.write(
+            "target_failed",
+            json!({
+                "worker": worker,
+                "target": target,
+                "error": reason(error),
+                "command": error.command,
+            }),
+        );
+    }
+
+    fn target_skipped(&self, target: &str) {
+        self.write("target_skipped", json!({ "target": target }));
+    }
+
+    fn warning(&self, worker: u64, target: &str, message: &str) {
+        self.write(
+            "warning",
+            json!({ "worker": worker, "target": target, "message": message }),
+        );
+    }
+
+    fn worker_exited(&self, worker: u64) {
+        self.write("worker_exited", json!({ "worker": worker }));
+    }
+
+    fn build_finished(&self, report: &BuildReport, duration: Duration) {
+        self.write(
+            "build_finished",
+            json!({
+                "success": report.is_success(),
+                "wall_time": duration.as_secs_f64(),
+                "succeeded": names(&report.succeeded),
+                "up_to_date": names(&report.up_to_date),
+                "restored": names(&report.restored),
+                "failed": names(&report.failed),
+                "skipped": names(&report.skipped),
+                "errors": report.errors,
+            }),
+        );
+    }
+}
@@ -1,13 +1,34 @@
+use std::io::Write;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
+use remake_lib::cache::remote::RemoteCache;
+use remake_lib::cache::LocalCache;
+use remake_lib::executer::interrupt;
+use remake_lib::executer::jobserver::Jobserver;
+use remake_lib::executer::{BuildReport, Executer};
+use remake_lib::graph;
+use remake_lib::state::BuildState;
+
+use clap::{Parser, Subcommand};
+
+mod event_log;
+mod trace;
+
+use event_log::EventLog;
+use trace::Trace;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// File to be read and executed.
-    #[arg(short, long)]
-    file: String,
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// File to be read and executed. The binary, text(.rmk), json and toml formats are accepted.
+    #[arg(short, long, required = true)]
+    file: Option<String>,
 
     /// Name of targets to be executed.
     #[arg(short, long)]
@@ -16,50 +37,501 @@ struct Args {
     /// How many threads we should use.
     #[arg(short, long, default_value_t = 1)]
     jobs: u32,
+
+    /// Keep executing the targets that do not depend on a failed target.
+    #[arg(short, long)]
+    keep_going: bool,
+
+    /// Execute the targets even if their outputs are up to date.
+    #[arg(short = 'B', long)]
+    always_make: bool,
+
+    /// The build state file. If set, a target is up to date when its commands and
+    /// the contents of its inputs did not change, instead of by the modified time.
+    #[arg(long)]
+    state: Option<String>,
+
+    /// Print why a target is executed.
+    #[arg(long)]
+    explain: bool,
+
+    /// The cache directory. The outputs of a target are restored from it instead of executing the target.
+    #[arg(long)]
+    cache: Option<String>,
+
+    /// The max size of the cache, like `512M` or `10G`.
+    /// The least recently used entries are removed when the cache is bigger.
+    #[arg(long, value_parser = parse_size)]
+    cache_size: Option<u64>,
+
+    /// The http cache server, like `http://127.0.0.1:8080`. It is used after the `--cache`.
+    #[arg(long)]
+    remote_cache: Option<String>,
+
+    /// Write the build events into the file as JSON Lines.
+    /// The outputs of the targets are written into `<EVENT_LOG>.outputs/`.
+    #[arg(long)]
+    event_log: Option<String>,
+
+    /// Write the build as a Chrome trace, to be opened in `about:tracing` or Perfetto.
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Print the critical path, the N slowest targets, the CPU time of the commands
+    /// and how busy the threads were after building.
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "10")]
+    time_report: Option<usize>,
+
+    /// Share the number of jobs with make and cargo by the GNU make jobserver.
+    /// Join the one in `MAKEFLAGS` if there is one, otherwise create one with `--jobs` slots for the commands
+    #[arg(long)]
+    jobserver: bool,
+
+    /// Do not start new targets while the 1-minute load average is over it, like `make -l`.
+    /// Only on Linux
+    #[arg(short, long, value_name = "LOAD")]
+    load_average: Option<f64>,
+
+    /// Do not start new targets while the available memory is under it, like `2G`. Only on Linux
+    #[arg(long, value_parser = parse_size)]
+    min_memory: Option<u64>,
+
+    /// How many seconds the running commands have to exit after Ctrl-C before they are killed
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    grace_period: u64,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Compile a build file into the binary format.
+    Compile {
+        /// The text, json or toml build file.
+        input: String,
+
+        /// Where to write the binary build file.
+        #[arg(short, long)]
+        output: String,
+    },
+    /// Manage a cache directory.
+    Cache {
+        /// The cache directory.
+        #[arg(short, long)]
+        dir: String,
+
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// Print the count and the size of the entries.
+    Stats,
+    /// Remove the least recently used entries until the cache is not bigger than the size.
+    Prune {
+        /// The max size, like `512M` or `10G`.
+        #[arg(value_parser = parse_size)]
+        max_size: u64,
+    },
+    /// Remove all the entries.
+    Clear,
+}
+
+/// Parse a size in bytes. The suffixes `K`, `M` and `G` are accepted.
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => size.split_at(index),
+        None => (size, ""),
+    };
+
+    let unit: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("unknown size unit `{}`", unit)),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| format!("invalid size `{}`", size))
+}
+
+fn open_cache(dir: &str) -> LocalCache {
+    match LocalCache::open(Path::new(dir)) {
+        Ok(cache) => cache,
+        Err(err) => {
+            eprintln!("Failed to open the cache `{}`:{}", dir, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn manage_cache(dir: &str, action: &CacheAction) {
+    let cache = open_cache(dir);
+
+    let result = match action {
+        CacheAction::Stats => cache.stats().map(|stats| {
+            println!("Entries:{}", stats.entries);
+            println!("Size:{} bytes", stats.size);
+        }),
+        CacheAction::Prune { max_size } => cache.evict(*max_size).map(|removed| {
+            println!(
+                "Removed {} entries, {} bytes",
+                removed.entries, removed.size
+            );
+        }),
+        CacheAction::Clear => cache.clear().map(|removed| {
+            println!(
+                "Removed {} entries, {} bytes",
+                removed.entries, removed.size
+            );
+        }),
+    };
+
+    if let Err(err) = result {
+        eprintln!("Failed to manage the cache `{}`:{}", dir, err);
+        std::process::exit(1);
+    }
+}
+
+/// Read a build file. The format is detected by the header or the extension.
+///
+/// - starts with `remake`: the binary format
+/// - `.json`: json
+/// - `.toml`: toml
+/// - `.rmk`: the text format
+fn load(file: &str) -> Result<remake_lib::parser::ParsedRemake, String> {
+    let bytes =
+        std::fs::read(file).map_err(|err| format!("Failed to read the `{}`:{}", file, err))?;
+
+    if bytes.starts_with(b"remake") {
+        return remake_lib::parser::parse_from_bytes(bytes)
+            .map_err(|err| format!("Failed to parse the `{}`:{}", file, err));
+    }
+
+    let extension = std::path::Path::new(file)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    let source = String::from_utf8(bytes)
+        .map_err(|_| format!("Failed to parse the `{}`:unknown format", file))?;
+
+    match extension.as_deref() {
+        Some("json") => remake_lib::format::interchange::parse_from_json(&source)
+            .map_err(|err| format!("Failed to parse the `{}`:{}", file, err)),
+        Some("toml") => remake_lib::format::interchange::parse_from_toml(&source)
+            .map_err(|err| format!("Failed to parse the `{}`:{}", file, err)),
+        Some("rmk") => remake_lib::parser::text::parse_from_text(&source)
+            .map_err(|err| format!("{}:{}", file, err)),
+        _ => {
+            if source.trim_start().starts_with('{') {
+                remake_lib::format::interchange::parse_from_json(&source)
+                    .map_err(|err| format!("Failed to parse the `{}`:{}", file, err))
+            } else {
+                Err(format!(
+                    "Failed to parse the `{}`:unknown format. Use the extension `.json`, `.toml` or `.rmk`",
+                    file
+                ))
+            }
+        }
+    }
+}
+
+/// Check the targets before use them.
+fn validate(
+    parsed: remake_lib::parser::ParsedRemake,
+) -> Result<remake_lib::parser::ParsedRemake, String> {
+    match remake_lib::graph::validate(&parsed) {
+        Ok(()) => Ok(parsed),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+fn compile(input: &str, output: &str) {
+    let parsed = match load(input).and_then(validate) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let bytes = remake_lib::format::writer::write_to_bytes(&parsed);
+
+    if let Err(err) = std::fs::write(output, bytes) {
+        eprintln!("Failed to write the `{}`:{}", output, err);
+        std::process::exit(1);
+    }
 }
 
 fn main() {
     let args = Args::parse();
 
-    let bytes = std::fs::read(&args.file).unwrap();
+    match &args.command {
+        Some(Commands::Compile { input, output }) => {
+            compile(input, output);
+            return;
+        }
+        Some(Commands::Cache { dir, action }) => {
+            manage_cache(dir, action);
+            return;
+        }
+        None => {}
+    }
+
+    let file = args.file.unwrap();
 
     // time begin
     let now = std::time::Instant::now();
 
-    let ret = remake_lib::parser::parse_from_bytes(bytes);
+    let ret = match load(&file).and_then(validate) {
+        Ok(ret) => ret,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-    if ret.is_err() {
-        eprintln!(
-            "Failed to parse the `{}`:{}",
-            &args.file,
-            ret.err().unwrap()
-        );
-        std::process::exit(1);
+    let mut executer = Executer::new(args.jobs, ret.targets);
+    executer.pools = ret.pools;
+    executer.throttle.max_load = args.load_average;
+    executer.throttle.min_memory = args.min_memory;
+    executer.grace_period = Duration::from_secs(args.grace_period);
+    executer.keep_going = args.keep_going;
+    executer.always_execute = args.always_make;
+    executer.explain = args.explain;
+    // the logger prints the outputs, so they never interleave
+    executer.capture_output = true;
+
+    if let Some(path) = &args.state {
+        let state = match BuildState::load(Path::new(path)) {
+            Ok(state) => state,
+            Err(err) => {
+                eprintln!("Ignore the broken build state `{}`:{}", path, err);
+                BuildState::new()
+            }
+        };
+        executer.build_state = Some(Arc::new(state));
+    }
+
+    if let Some(dir) = &args.cache {
+        let mut cache = open_cache(dir);
+        cache.max_size = args.cache_size;
+        executer.caches.push(Arc::new(cache));
     }
 
-    let ret = ret.unwrap();
+    if let Some(url) = &args.remote_cache {
+        match RemoteCache::new(url) {
+            Ok(cache) => executer.caches.push(Arc::new(cache)),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.jobserver {
+        match Jobserver::from_env() {
+            Ok(Some(jobserver)) => executer.jobserver = Some(Arc::new(jobserver)),
+            Ok(None) => match Jobserver::new(args.jobs.max(1)) {
+                Ok(jobserver) => executer.jobserver = Some(Arc::new(jobserver)),
+                Err(err) => {
+                    eprintln!("Failed to create a jobserver:{}", err);
+                    std::process::exit(1);
+                }
+            },
+            // like make, build without it
+            Err(err) => eprintln!("Failed to join the jobserver:{}", err),
+        }
+    }
+
+    if let Some(path) = &args.event_log {
+        match EventLog::create(path) {
+            Ok(log) => executer.observers.push(Arc::new(log)),
+            Err(err) => {
+                eprintln!("Failed to create the event log `{}`:{}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let mut executer = remake_lib::executer::Executer::new(args.jobs, ret.targets);
+    if let Some(path) = &args.trace {
+        match Trace::create(path) {
+            Ok(trace) => executer.observers.push(Arc::new(trace)),
+            Err(err) => {
+                eprintln!("Failed to create the trace `{}`:{}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    executer.logger = Arc::new(|msg| -> () {
-        println!("{}", msg);
-        ()
+    // never panic when the stdout is closed, or the other workers wait for the panicked one forever
+    executer.logger = Arc::new(|msg| {
+        let _ = writeln!(std::io::stdout().lock(), "{}", msg);
     });
 
-    let executed = executer.execute(&args.targets);
+    // forward Ctrl-C to the commands and save the build state before exiting
+    if let Err(err) = interrupt::install() {
+        if err.kind() != std::io::ErrorKind::Unsupported {
+            eprintln!("Failed to handle the interrupts:{}", err);
+        }
+    }
+
+    let begin = std::time::Instant::now();
+    let cpu_begin = children_cpu_time();
+    let report = executer.execute(&args.targets);
+    let wall_time = begin.elapsed();
+    let cpu_time = match (cpu_begin, children_cpu_time()) {
+        (Some(begin), Some(end)) => Some(end.saturating_sub(begin)),
+        _ => None,
+    };
 
-    let errors = executed.lock();
+    if let (Some(path), Some(state)) = (&args.state, &executer.build_state) {
+        if let Err(err) = state.save(Path::new(path)) {
+            eprintln!("Failed to write the build state `{}`:{}", path, err);
+        }
+    }
 
     // time end
 
-    if errors.len() != 0 {
-        for error in errors.iter() {
+    if !report.is_success() {
+        for error in report.errors.iter() {
             eprintln!("Runtime Error:{}", error)
         }
     } else {
         println!("Finished")
     }
 
+    print_targets("Succeeded", &report.succeeded);
+    print_targets("Up to date", &report.up_to_date);
+    print_targets("Restored from the cache", &report.restored);
+    print_targets("Failed", &report.failed);
+    print_targets("Skipped because of failed dependences", &report.skipped);
+
+    if let Some(top) = args.time_report {
+        print_time_report(&report, &executer, wall_time, cpu_time, top);
+    }
+
     let used = now.elapsed();
     println!("Cost {}s {}ms", used.as_secs(), used.subsec_millis());
+
+    // like a shell does for a program killed by the signal
+    if let Some(signal) = report.interrupted {
+        std::process::exit(128 + signal);
+    }
+
+    if !report.is_success() {
+        std::process::exit(1);
+    }
+}
+
+fn print_targets(title: &str, targets: &[Arc<String>]) {
+    if targets.is_empty() {
+        return;
+    }
+
+    println!("{}({}):", title, targets.len());
+
+    for target in targets {
+        println!("    {}", target);
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{}s {}ms", duration.as_secs(), duration.subsec_millis())
+}
+
+/// The user and system CPU time of the child processes that exited, and their children.
+/// Only on unix.
+#[cfg(unix)]
+fn children_cpu_time() -> Option<Duration> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return None;
+    }
+
+    let time = |t: libc::timeval| {
+        Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+    };
+    Some(time(usage.ru_utime) + time(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn children_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// Print where the time of a build went.
+///
+/// The CPU time is how long the commands ran on the CPUs, measured by `children_cpu_time`.
+/// More than the wall time means they ran in parallel.
+///
+/// The target time is the sum of the durations of the targets, that is how long the threads were busy,
+/// including waiting for the commands. The utilization is it divided by the wall time of all the threads.
+fn print_time_report(
+    report: &BuildReport,
+    executer: &Executer,
+    wall_time: Duration,
+    cpu_time: Option<Duration>,
+    top: usize,
+) {
+    let path = graph::critical_path(&executer.all_targets, &report.durations);
+
+    println!(
+        "Critical path({}) {}:",
+        path.targets.len(),
+        format_duration(path.duration)
+    );
+    for target in path.targets.iter() {
+        println!(
+            "    {} {}",
+            target,
+            format_duration(report.durations[target])
+        );
+    }
+
+    let mut slowest: Vec<(&Arc<String>, &Duration)> = report.durations.iter().collect();
+    slowest.sort_unstable_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    slowest.truncate(top);
+
+    println!("Slowest targets({}):", slowest.len());
+    for (target, duration) in slowest {
+        println!("    {} {}", target, format_duration(*duration));
+    }
+
+    let target_time: Duration = report.durations.values().sum();
+    let threads = executer.thread_count.max(1);
+
+    let utilization = if wall_time.is_zero() {
+        0.0
+    } else {
+        target_time.as_secs_f64() / (wall_time.as_secs_f64() * threads as f64) * 100.0
+    };
+
+    match cpu_time {
+        Some(cpu_time) => println!(
+            "CPU time {}, wall time {}, {:.2}x",
+            format_duration(cpu_time),
+            format_duration(wall_time),
+            if wall_time.is_zero() {
+                0.0
+            } else {
+                cpu_time.as_secs_f64() / wall_time.as_secs_f64()
+            }
+        ),
+        None => println!("CPU time unknown, wall time {}", format_duration(wall_time)),
+    }
+
+    println!(
+        "Thread utilization {:.1}% of {} threads, target time {}",
+        utilization,
+        threads,
+        format_duration(target_time)
+    );
 }
@@ -0,0 +1,217 @@
+//! Write a build as a Chrome trace. See docs/trace.md.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use remake_lib::errors::RuntimeError;
+use remake_lib::executer::observer::BuildObserver;
+use remake_lib::executer::scheduler::TargetState;
+use remake_lib::executer::up_to_date::Staleness;
+use remake_lib::executer::BuildReport;
+use remake_lib::format::{Command, CommandRun};
+use serde_json::{json, Value};
+
+/// A target being built by a worker.
+struct Span {
+    target: String,
+    begin: Duration,
+    reason: Option<String>,
+}
+
+/// Collect the spans of the targets and the commands, and write them when the build finishes.
+///
+/// Every worker is a track. It can be opened in `about:tracing` or Perfetto.
+pub struct Trace {
+    file: Mutex<Option<File>>,
+    path: String,
+    /// Reset when the build starts, so the setup before it is not in the trace.
+    begin: Mutex<Instant>,
+    events: Mutex<Vec<Value>>,
+    /// A worker builds one target at a time.
+    spans: Mutex<HashMap<u64, Span>>,
+}
+
+/// The microseconds of the trace format.
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e6
+}
+
+impl Trace {
+    /// Create the trace file now, so a bad path is found before building.
+    pub fn create(path: &str) -> io::Result<Trace> {
+        Ok(Trace {
+            file: Mutex::new(Some(File::create(path)?)),
+            path: String::from(path),
+            begin: Mutex::new(Instant::now()),
+            events: Mutex::new(Vec::new()),
+            spans: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The time since the build started.
+    fn now(&self) -> Duration {
+        self.begin.lock().unwrap().elapsed()
+    }
+
+    fn push(&self, event: Value) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// End the span of the target that the worker is building.
+    fn end_target(&self, worker: u64, state: &str, error: Option<&RuntimeError>) {
+        let span = match self.spans.lock().unwrap().remove(&worker) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let end = self.now();
+
+        self.push(json!({
+            "name": span.target,
+            "cat": "target",
+            "ph": "X",
+            "ts": micros(span.begin),
+            "dur": micros(end.saturating_sub(span.begin)),
+            "pid": 1,
+            "tid": worker,
+            "args": {
+                "state": state,
+                "reason": span.reason,
+                "error": error.map(|e| e.to_string()),
+            },
+        }));
+    }
+
+    fn write(&self, file: File, events: Vec<Value>) -> io::Result<()> {
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(
+            &mut writer,
+            &json!({ "traceEvents": events, "displayTimeUnit": "ms" }),
+        )?;
+        writer.flush()
+    }
+}
+
+impl BuildObserver for Trace {
+    fn build_started(&self, _targets: &[String]) {
+        *self.begin.lock().unwrap() = Instant::now();
+    }
+
+    fn target_scheduled(&self, worker: u64, target: &str) {
+        self.spans.lock().unwrap().insert(
+            worker,
+            Span {
+                target: String::from(target),
+                begin: self.now(),
+                reason: None,
+            },
+        );
+    }
+
+    fn target_started(&self, worker: u64, _target: &str, staleness: &Staleness) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&worker) {
+            span.reason = Some(staleness.to_string());
+        }
+    }
+
+    fn command_exited(&self, worker: u64, target: &str, command: &Command, run: &CommandRun) {
+        // the duration begins before spawning, so a command that failed to spawn has a span too
+        let end = self.now();
+
+        self.push(json!({
+            "name": command.executable.as_str(),
+            "cat": "command",
+            "ph": "X",
+            "ts": micros(end.saturating_sub(run.duration)),
+            "dur": micros(run.duration),
+            "pid": 1,
+            "tid": worker,
+            "args": {
+                "target": target,
+                "arguments": command.arguments.iter().map(|a| a.as_str()).collect::<Vec<_>>(),
+                "pid": run.pid,
+                "exit_code": run.exit_code,
+            },
+        }));
+    }
+
+    fn target_finished(&self, worker: u64, _target: &str, state: TargetState) {
+        let state = match state {
+            TargetState::UpToDate => "up_to_date",
+            TargetState::Restored => "restored",
+            _ => "succeeded",
+        };
+        self.end_target(worker, state, None);
+    }
+
+    fn target_failed(&self, worker: u64, _target: &str, error: &RuntimeError) {
+        self.end_target(worker, "failed", Some(error));
+    }
+
+    fn warning(&self, worker: u64, target: &str, message: &str) {
+        self.push(json!({
+            "name": message,
+            "cat": "warning",
+            "ph": "i",
+            "s": "t",
+            "ts": micros(self.now()),
+            "pid": 1,
+            "tid": worker,
+            "args": { "target": target },
+        }));
+    }
+
+    fn build_finished(&self, report: &BuildReport, duration: Duration) {
+        let mut events = std::mem::take(&mut *self.events.lock().unwrap());
+
+        // name the tracks
+        let workers: BTreeSet<u64> = events.iter().filter_map(|e| e["tid"].as_u64()).collect();
+
+        events.push(json!({
+            "name": "process_name",
+            "ph": "M",
+            "pid": 1,
+            "args": { "name": "remake" },
+        }));
+        // the same names as the log
+        for worker in workers {
+            events.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": worker,
+                "args": { "name": format!("Thread {}", worker) },
+            }));
+        }
+
+        events.push(json!({
+            "name": "build",
+            "cat": "build",
+            "ph": "X",
+            "ts": 0,
+            "dur": micros(duration),
+            "pid": 1,
+            "tid": 0,
+            "args": {
+                "success": report.is_success(),
+                "errors": report.errors,
+            },
+        }));
+        events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": 1,
+            "tid": 0,
+            "args": { "name": "Build" },
+        }));
+
+        if let Some(file) = self.file.lock().unwrap().take() {
+            if let Err(err) = self.write(file, events) {
+                eprintln!("Failed to write the trace `{}`:{}", self.path, err);
+            }
+        }
+    }
+}